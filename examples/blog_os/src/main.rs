@@ -128,6 +128,8 @@ pub fn new_kernel_context(entry: extern "C" fn(usize) -> !, arg0: usize) -> Box<
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     serial_println!("\n{}", info);
+    // only the panicking thread exits, if any
+    thread::terminate_current_on_panic(info);
 
     unsafe {
         exit_qemu();
@@ -119,7 +119,11 @@ mod dummy {
     pub unsafe fn restore(_flags: usize) {}
 
     #[inline]
-    pub unsafe fn enable_and_wfi() {}
+    pub unsafe fn enable_and_wfi() {
+        // no interrupt to wait for, but an idle CPU in tests shouldn't spin hot
+        #[cfg(test)]
+        std::thread::sleep(core::time::Duration::from_millis(1));
+    }
 }
 
 /// Execute function `f` with interrupt disabled.
@@ -11,8 +11,13 @@ mod interrupt;
 mod processor;
 pub mod scheduler;
 pub mod std_thread;
+pub mod sync;
+#[cfg(all(test, feature = "userland"))]
+mod test_util;
 mod thread_pool;
+mod thread_table;
 mod timer;
+mod wait_queue;
 
 #[cfg(target_arch = "x86_64")]
 #[path = "./context/x86_64.rs"]
@@ -32,3 +37,4 @@ pub mod context;
 
 pub use crate::processor::Processor;
 pub use crate::thread_pool::*;
+pub use crate::wait_queue::WaitQueue;
@@ -68,6 +68,7 @@ impl Processor {
         loop {
             if let Some(thread) = inner.manager.run(inner.id) {
                 trace!("CPU{} begin running thread {}", inner.id, thread.0);
+                inner.manager.program_next_event(inner.id, Some(thread.0));
                 inner.thread = Some(thread);
                 unsafe {
                     inner
@@ -79,6 +80,7 @@ impl Processor {
                 inner.manager.stop(tid, context);
             } else {
                 trace!("CPU{} idle", inner.id);
+                inner.manager.program_next_event(inner.id, None);
                 unsafe {
                     interrupt::enable_and_wfi();
                     // wait for a timer interrupt
@@ -90,7 +92,31 @@ impl Processor {
 
     /// Called by process running on this Processor.
     /// Yield and reschedule.
+    ///
+    /// It's a cancellation point: a killed thread exits before switching out.
+    /// The caller should call `test_cancel` on the current `Processor` after switched in,
+    /// which may be another one.
     pub(crate) fn yield_now(&self) {
+        self.test_cancel();
+        self.switch_out();
+    }
+
+    /// A cancellation point of the current thread:
+    /// exit it if it's killed and the cancellation is not deferred.
+    pub(crate) fn test_cancel(&self) {
+        let inner = self.inner();
+        let tid = inner.thread.as_ref().unwrap().0;
+        if inner.manager.test_cancel(tid) {
+            self.switch_out();
+            unreachable!("thread {} exited", tid);
+        }
+    }
+
+    /// Switch back to the loop of `run`, without being a cancellation point.
+    ///
+    /// Used to preempt the current thread, which must not be cancelled
+    /// at an arbitrary point, and by `sync` to block, which handles the cancellation itself.
+    pub(crate) fn switch_out(&self) {
         let inner = self.inner();
         unsafe {
             inner
@@ -102,6 +128,11 @@ impl Processor {
         }
     }
 
+    /// Get the id of this CPU, as passed to `init`.
+    pub fn id(&self) -> usize {
+        self.inner().id
+    }
+
     /// Get tid of current running thread.
     /// This will panic if this CPU is idle.
     pub fn tid(&self) -> Tid {
@@ -128,6 +159,9 @@ impl Processor {
 
     /// Called by timer interrupt handler.
     ///
+    /// It should be called on every CPU, since each CPU drives the timers
+    /// of the threads slept on it. See `ThreadPool::migrate_timers` for CPUs going offline.
+    ///
     /// The interrupt should be disabled in the handler.
     pub fn tick(&self) {
         // If I'm idle, tid == None, need_reschedule == false.
@@ -135,7 +169,7 @@ impl Processor {
         let tid = self.inner().thread.as_ref().map(|p| p.0);
         let need_reschedule = self.manager().tick(self.inner().id, tid);
         if need_reschedule {
-            self.yield_now();
+            self.switch_out();
         }
     }
 }
@@ -0,0 +1,281 @@
+//! Completely Fair Scheduler, CFS-style
+//!
+//! Each task accumulates a virtual runtime: the ticks it ran, scaled inversely to its weight.
+//! The task with least virtual runtime, the leftmost in an ordered tree, is selected to run.
+//!
+//! Every ready task runs once within a target latency, for a slice proportional to its weight.
+//! A task waking up is placed near the least virtual runtime,
+//! so it can't hoard credit by sleeping.
+
+use super::*;
+use alloc::collections::BTreeSet;
+
+pub struct CfsScheduler {
+    inner: Mutex<CfsSchedulerInner>,
+}
+
+struct CfsSchedulerInner {
+    /// Period in ticks in which every ready task should run once.
+    target_latency: usize,
+    /// The least ticks a task runs before being preempted.
+    min_granularity: usize,
+    infos: Vec<CfsProcInfo>,
+    /// Ready tasks ordered by (vruntime, tid).
+    tree: BTreeSet<(u64, Tid)>,
+    /// Total weight of the tasks in the tree.
+    total_weight: u64,
+    /// Monotonic lower bound of vruntime of the ready and running tasks.
+    min_vruntime: u64,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct CfsProcInfo {
+    present: bool,
+    /// Ever pushed. A new task starts at `min_vruntime`.
+    seen: bool,
+    priority: u8,
+    vruntime: u64,
+    /// Ticks of the slice given when popped.
+    slice: usize,
+    rest_slice: usize,
+}
+
+/// Weight of a task of nice 0.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Virtual runtime of a nice 0 task running for a tick.
+const TICK_VRUNTIME: u64 = 1024;
+
+/// Weights of nice -20 to 19, the same as Linux.
+/// Each step is about 1.25x CPU share.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110,
+    87, 70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+impl CfsProcInfo {
+    /// Priority 1, the default, is nice 0. Each priority above it is one nice level higher,
+    /// up to nice -20 at 21. Priority 0 is nice 1.
+    fn weight(&self) -> u64 {
+        let nice = 21 - (self.priority as usize).min(21);
+        NICE_TO_WEIGHT[nice]
+    }
+
+    /// Charge `ticks` of running.
+    fn charge(&mut self, ticks: usize) {
+        self.vruntime += ticks as u64 * TICK_VRUNTIME * NICE_0_WEIGHT / self.weight();
+    }
+}
+
+impl Scheduler for CfsScheduler {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+    }
+    fn pop(&self, _cpu_id: usize) -> Option<usize> {
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        self.inner.lock().tick_n(current_tid, 1)
+    }
+    fn tick_n(&self, current_tid: usize, n: usize) -> bool {
+        self.inner.lock().tick_n(current_tid, n)
+    }
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner.lock().remaining_slice(current_tid)
+    }
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.inner.lock().set_priority(tid, priority);
+    }
+    fn cal_priority(&self, priority: u8) -> u8 {
+        priority
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn start(&self, _tid: usize) {}
+    fn get_tick(&self, _tid: usize) -> u8 {1}
+    fn end(&self, _tid: usize) {}
+
+    fn set_success(&self, _tid:usize, _value: bool) {}
+    fn get_success(&self, _tid:usize) -> bool {true}
+    fn reset_slice(&self, _tid:usize) {}
+}
+
+impl CfsScheduler {
+    /// Create a scheduler running every ready task once within `target_latency` ticks,
+    /// but for at least `min_granularity` ticks each time.
+    pub fn new(target_latency: usize, min_granularity: usize) -> Self {
+        let min_granularity = min_granularity.max(1);
+        let inner = CfsSchedulerInner {
+            target_latency: target_latency.max(min_granularity),
+            min_granularity,
+            infos: Vec::default(),
+            tree: BTreeSet::new(),
+            total_weight: 0,
+            min_vruntime: 0,
+        };
+        CfsScheduler {
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+impl CfsSchedulerInner {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        // a sleeper gets credit of at most half a latency
+        let bonus = self.target_latency as u64 * TICK_VRUNTIME / 2;
+        let floor = self.min_vruntime.saturating_sub(bonus);
+        let info = &mut self.infos[tid];
+        assert!(!info.present);
+        info.present = true;
+        if !info.seen {
+            info.seen = true;
+            info.vruntime = self.min_vruntime;
+        } else {
+            info.vruntime = info.vruntime.max(floor);
+        }
+        self.tree.insert((info.vruntime, tid));
+        self.total_weight += info.weight();
+        trace!("cfs push {} vruntime {}", tid, info.vruntime);
+    }
+
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        let &(leftmost, _) = self.tree.iter().next()?;
+        let &(vruntime, tid) = self.tree.iter().find(|&&(_, tid)| allowed(tid))?;
+        self.tree.remove(&(vruntime, tid));
+        let weight = self.infos[tid].weight();
+        // the slice of its weight in a period, counting itself
+        let total_weight = self.total_weight;
+        self.total_weight -= weight;
+        let nr_running = self.tree.len() + 1;
+        let period = self
+            .target_latency
+            .max(nr_running * self.min_granularity);
+        let slice = (period as u64 * weight / total_weight) as usize;
+        let slice = slice.max(self.min_granularity);
+        self.min_vruntime = self.min_vruntime.max(leftmost);
+        let info = &mut self.infos[tid];
+        info.present = false;
+        info.slice = slice;
+        info.rest_slice = slice;
+        trace!("cfs pop {} vruntime {} slice {}", tid, vruntime, slice);
+        Some(tid)
+    }
+
+    fn remaining_slice(&self, current: Tid) -> usize {
+        self.infos
+            .get(current)
+            .map_or(1, |info| info.rest_slice.max(1))
+    }
+
+    fn tick_n(&mut self, current: Tid, n: usize) -> bool {
+        expand(&mut self.infos, current);
+        let info = &mut self.infos[current];
+        assert!(!info.present);
+        info.charge(n);
+        info.rest_slice = info.rest_slice.saturating_sub(n);
+        if info.rest_slice == 0 {
+            return true;
+        }
+        // preempt early for a task far behind, after running the least ticks
+        let ran = info.slice - info.rest_slice;
+        let vruntime = info.vruntime;
+        match self.tree.iter().next() {
+            Some(&(leftmost, _)) => {
+                ran >= self.min_granularity
+                    && vruntime > leftmost + self.min_granularity as u64 * TICK_VRUNTIME
+            }
+            None => false,
+        }
+    }
+
+    fn set_priority(&mut self, tid: Tid, priority: u8) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            self.total_weight -= info.weight();
+            info.priority = priority;
+            self.total_weight += info.weight();
+        } else {
+            info.priority = priority;
+        }
+        trace!("cfs {} priority = {}", tid, priority);
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            info.present = false;
+            self.tree.remove(&(info.vruntime, tid));
+            self.total_weight -= info.weight();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+
+    /// Run on one CPU for `ticks` ticks, returning ticks run by each task.
+    fn run(scheduler: &CfsScheduler, ticks: usize, tasks: usize) -> Vec<usize> {
+        let mut ran = vec![0; tasks];
+        let mut current = scheduler.pop(0).unwrap();
+        for _ in 0..ticks {
+            ran[current] += 1;
+            if scheduler.tick(current) {
+                scheduler.push(current);
+                current = scheduler.pop(0).unwrap();
+            }
+        }
+        ran
+    }
+
+    #[test]
+    fn equal_share() {
+        let scheduler = CfsScheduler::new(12, 2);
+        for tid in 0..3 {
+            scheduler.set_priority(tid, 1);
+            scheduler.push(tid);
+        }
+        let ran = run(&scheduler, 1200, 3);
+        for &ticks in ran.iter() {
+            assert!(ticks >= 390 && ticks <= 410, "{:?}", ran);
+        }
+    }
+
+    #[test]
+    fn weighted_share() {
+        let scheduler = CfsScheduler::new(20, 1);
+        // nice 0 and nice -5: 1024 vs 3121
+        scheduler.set_priority(0, 1);
+        scheduler.set_priority(1, 6);
+        scheduler.push(0);
+        scheduler.push(1);
+        let ran = run(&scheduler, 4145, 2);
+        assert!(ran[0] >= 1000 && ran[0] <= 1050, "{:?}", ran);
+    }
+
+    #[test]
+    fn sleeper_placed_near_min_vruntime() {
+        let scheduler = CfsScheduler::new(10, 1);
+        scheduler.set_priority(0, 1);
+        scheduler.set_priority(1, 1);
+        scheduler.push(0);
+        scheduler.push(1);
+        // task 1 goes to sleep while task 0 runs alone for long
+        scheduler.remove(1);
+        run(&scheduler, 1000, 1);
+        scheduler.push(0);
+        scheduler.push(1);
+        // task 1 runs first, but only for its credit of half a latency
+        let ran = run(&scheduler, 20, 2);
+        assert!(ran[1] <= 15, "{:?}", ran);
+        assert!(ran[0] >= 5, "{:?}", ran);
+    }
+}
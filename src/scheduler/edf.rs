@@ -0,0 +1,412 @@
+//! Earliest deadline first scheduler, for real-time tasks with reservations
+//!
+//! A task with a `Reservation` runs for `runtime` ticks in each period,
+//! and among the ready ones, the task with the earliest deadline is selected to run.
+//! A task using up its budget is throttled until the next period, when it's replenished.
+//!
+//! Reservations pass admission control: their total utilization (`runtime` / `period`)
+//! may not exceed 95% of each CPU, like `SCHED_DEADLINE` of Linux.
+//! On one CPU, this guarantees all deadlines equal to periods.
+//! On several CPUs, or with deadlines shorter than periods,
+//! some may still be missed. They are counted by `deadline_misses`.
+//!
+//! Tasks without reservations run round robin in the background, when no real-time task is ready.
+
+use super::*;
+use alloc::collections::BTreeSet;
+
+pub struct EdfScheduler {
+    inner: Mutex<EdfSchedulerInner>,
+}
+
+struct EdfSchedulerInner {
+    cpus: usize,
+    /// Time slice of background tasks.
+    max_time_slice: usize,
+    /// The current tick.
+    now: usize,
+    /// Total utilization of admitted reservations, in millionths of a CPU.
+    utilization: u64,
+    infos: Vec<EdfProcInfo>,
+    /// Real-time tasks ready with budget, ordered by (absolute deadline, tid).
+    ready: BTreeSet<(usize, Tid)>,
+    /// Real-time tasks ready but out of budget, ordered by (next release, tid).
+    throttled: BTreeSet<(usize, Tid)>,
+    /// Ready tasks without reservations.
+    background: VecDeque<Tid>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct EdfProcInfo {
+    present: bool,
+    reservation: Option<Reservation>,
+    /// Start of the current period.
+    release: usize,
+    /// Absolute deadline in the current period.
+    deadline: usize,
+    /// Ticks left to run in the current period.
+    budget: usize,
+    /// A miss in the current period has been counted.
+    missed: bool,
+    misses: usize,
+    /// Ticks left of a background task.
+    rest_slice: usize,
+}
+
+/// Utilization the admission control allows on each CPU, in millionths.
+const MAX_UTILIZATION: u64 = 950_000;
+
+/// Utilization of `reservation` in millionths of a CPU, rounding up.
+fn utilization(reservation: &Reservation) -> u64 {
+    let runtime = reservation.runtime as u64 * 1_000_000;
+    let period = reservation.period as u64;
+    (runtime + period - 1) / period
+}
+
+impl EdfProcInfo {
+    /// Start a new period if the current one ended at `now`, replenishing the budget.
+    fn replenish(&mut self, now: usize) {
+        let reservation = match self.reservation {
+            Some(reservation) => reservation,
+            None => return,
+        };
+        if now < self.release + reservation.period {
+            return;
+        }
+        let periods = (now - self.release) / reservation.period;
+        self.start_period(self.release + periods * reservation.period);
+    }
+
+    fn start_period(&mut self, release: usize) {
+        let reservation = self.reservation.unwrap();
+        self.release = release;
+        self.deadline = release + reservation.deadline;
+        self.budget = reservation.runtime;
+        self.missed = false;
+    }
+
+    /// Count a miss if it still needs to run past its deadline.
+    fn check_miss(&mut self, now: usize) {
+        if self.reservation.is_some() && self.budget > 0 && now >= self.deadline && !self.missed {
+            self.missed = true;
+            self.misses += 1;
+        }
+    }
+}
+
+impl Scheduler for EdfScheduler {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+    }
+    fn pop(&self, _cpu_id: usize) -> Option<usize> {
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        self.inner.lock().tick(current_tid)
+    }
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner.lock().remaining_slice(current_tid)
+    }
+    fn set_time(&self, now: usize) {
+        self.inner.lock().set_time(now);
+    }
+    fn reserve(&self, tid: usize, reservation: Option<Reservation>) -> Result<(), ReserveError> {
+        self.inner.lock().reserve(tid, reservation)
+    }
+    fn deadline_misses(&self, tid: usize) -> usize {
+        self.inner
+            .lock()
+            .infos
+            .get(tid)
+            .map_or(0, |info| info.misses)
+    }
+    fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn cal_priority(&self, priority: u8) -> u8 {
+        priority
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn start(&self, _tid: usize) {}
+    fn get_tick(&self, _tid: usize) -> u8 {1}
+    fn end(&self, _tid: usize) {}
+
+    fn set_success(&self, _tid:usize, _value: bool) {}
+    fn get_success(&self, _tid:usize) -> bool {true}
+    fn reset_slice(&self, _tid:usize) {}
+}
+
+impl EdfScheduler {
+    /// Create a scheduler for `cpus` CPUs.
+    /// Background tasks run round robin for `max_time_slice` ticks each time.
+    pub fn new(cpus: usize, max_time_slice: usize) -> Self {
+        let inner = EdfSchedulerInner {
+            cpus: cpus.max(1),
+            max_time_slice: max_time_slice.max(1),
+            now: 0,
+            utilization: 0,
+            infos: Vec::default(),
+            ready: BTreeSet::new(),
+            throttled: BTreeSet::new(),
+            background: VecDeque::new(),
+        };
+        EdfScheduler {
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+impl EdfSchedulerInner {
+    /// Put a present task in the queue of its state.
+    fn enqueue(&mut self, tid: Tid) {
+        let info = &self.infos[tid];
+        match info.reservation {
+            Some(reservation) if info.budget == 0 => {
+                self.throttled.insert((info.release + reservation.period, tid));
+            }
+            Some(_) => {
+                self.ready.insert((info.deadline, tid));
+            }
+            None => self.background.push_back(tid),
+        }
+    }
+
+    /// Take a present task out of the queue of its state.
+    fn dequeue(&mut self, tid: Tid) {
+        let info = &self.infos[tid];
+        match info.reservation {
+            Some(reservation) if info.budget == 0 => {
+                self.throttled.remove(&(info.release + reservation.period, tid));
+            }
+            Some(_) => {
+                self.ready.remove(&(info.deadline, tid));
+            }
+            None => {
+                if let Some(index) = self.background.iter().position(|&t| t == tid) {
+                    self.background.remove(index);
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let now = self.now;
+        let max_time_slice = self.max_time_slice;
+        let info = &mut self.infos[tid];
+        assert!(!info.present);
+        info.present = true;
+        info.replenish(now);
+        if info.rest_slice == 0 {
+            info.rest_slice = max_time_slice;
+        }
+        self.enqueue(tid);
+        trace!("edf push {}", tid);
+    }
+
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        let tid = match self.ready.iter().find(|&&(_, tid)| allowed(tid)) {
+            Some(&(deadline, tid)) => {
+                self.ready.remove(&(deadline, tid));
+                tid
+            }
+            None => {
+                let index = self.background.iter().position(|&tid| allowed(tid))?;
+                self.background.remove(index).unwrap()
+            }
+        };
+        self.infos[tid].present = false;
+        trace!("edf pop {}", tid);
+        Some(tid)
+    }
+
+    fn set_time(&mut self, now: usize) {
+        if now <= self.now {
+            return;
+        }
+        self.now = now;
+        // release the throttled tasks whose new period began
+        while let Some(&(release, tid)) = self.throttled.iter().next() {
+            if release > now {
+                break;
+            }
+            self.throttled.remove(&(release, tid));
+            self.infos[tid].replenish(now);
+            self.enqueue(tid);
+        }
+        // count the misses of ready tasks not run in time, and renew their periods
+        let expired: Vec<(usize, Tid)> = self.ready.range(..(now + 1, 0)).cloned().collect();
+        for (deadline, tid) in expired {
+            let info = &mut self.infos[tid];
+            info.check_miss(now);
+            info.replenish(now);
+            if info.deadline != deadline {
+                self.ready.remove(&(deadline, tid));
+                self.enqueue(tid);
+            }
+        }
+    }
+
+    fn remaining_slice(&self, current: Tid) -> usize {
+        let rest = match self.infos.get(current) {
+            Some(info) if info.reservation.is_some() => info.budget,
+            Some(info) => info.rest_slice,
+            None => 1,
+        };
+        // wake up in time to release throttled tasks
+        let release = self
+            .throttled
+            .iter()
+            .next()
+            .map_or(usize::max_value(), |&(release, _)| release.saturating_sub(self.now));
+        rest.min(release).max(1)
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        let now = self.now;
+        let earliest = self.ready.iter().next().map(|&(deadline, _)| deadline);
+        let info = &mut self.infos[current];
+        assert!(!info.present);
+        if info.reservation.is_some() {
+            // the tick just ended belongs to the current period
+            info.budget = info.budget.saturating_sub(1);
+            info.check_miss(now);
+            info.replenish(now);
+            // throttled on push when out of budget
+            info.budget == 0 || earliest.map_or(false, |deadline| deadline < info.deadline)
+        } else {
+            if info.rest_slice > 0 {
+                info.rest_slice -= 1;
+            }
+            info.rest_slice == 0 || earliest.is_some()
+        }
+    }
+
+    fn reserve(&mut self, tid: Tid, reservation: Option<Reservation>) -> Result<(), ReserveError> {
+        if let Some(r) = reservation {
+            if r.runtime == 0 || r.runtime > r.deadline || r.deadline > r.period {
+                return Err(ReserveError::Invalid);
+            }
+        }
+        expand(&mut self.infos, tid);
+        let old = self.infos[tid].reservation.as_ref().map_or(0, utilization);
+        let new = reservation.as_ref().map_or(0, utilization);
+        let utilization = self.utilization - old + new;
+        if new > old && utilization > self.cpus as u64 * MAX_UTILIZATION {
+            return Err(ReserveError::Overloaded);
+        }
+        self.utilization = utilization;
+        let present = self.infos[tid].present;
+        if present {
+            self.dequeue(tid);
+        }
+        let now = self.now;
+        let info = &mut self.infos[tid];
+        if info.reservation.is_none() {
+            // maybe a new thread in the slot
+            info.misses = 0;
+        }
+        info.reservation = reservation;
+        if reservation.is_some() {
+            info.start_period(now);
+        }
+        if present {
+            self.enqueue(tid);
+        }
+        trace!("edf {} reservation = {:?}", tid, reservation);
+        Ok(())
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        if self.infos[tid].present {
+            self.dequeue(tid);
+            self.infos[tid].present = false;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+
+    fn reservation(runtime: usize, deadline: usize, period: usize) -> Option<Reservation> {
+        Some(Reservation {
+            runtime,
+            deadline,
+            period,
+        })
+    }
+
+    /// Run on one CPU from tick `from` to `to`, returning the task run at each tick.
+    fn run(scheduler: &EdfScheduler, from: usize, to: usize) -> Vec<Option<Tid>> {
+        let mut trace = Vec::new();
+        let mut current = None;
+        for now in from..to {
+            scheduler.set_time(now);
+            if current.is_none() {
+                current = scheduler.pop(0);
+            }
+            trace.push(current);
+            if let Some(tid) = current {
+                scheduler.set_time(now + 1);
+                if scheduler.tick(tid) {
+                    scheduler.push(tid);
+                    current = None;
+                }
+            }
+        }
+        if let Some(tid) = current {
+            scheduler.push(tid);
+        }
+        trace
+    }
+
+    #[test]
+    fn admission_control() {
+        let scheduler = EdfScheduler::new(1, 5);
+        assert_eq!(scheduler.reserve(0, reservation(0, 5, 10)), Err(ReserveError::Invalid));
+        assert_eq!(scheduler.reserve(0, reservation(6, 5, 10)), Err(ReserveError::Invalid));
+        assert_eq!(scheduler.reserve(0, reservation(5, 10, 10)), Ok(()));
+        assert_eq!(scheduler.reserve(1, reservation(5, 10, 10)), Err(ReserveError::Overloaded));
+        assert_eq!(scheduler.reserve(1, reservation(4, 10, 10)), Ok(()));
+        // shrinking is always fine, and frees room
+        assert_eq!(scheduler.reserve(0, None), Ok(()));
+        assert_eq!(scheduler.reserve(1, reservation(9, 10, 10)), Ok(()));
+    }
+
+    #[test]
+    fn budget_and_replenish() {
+        let scheduler = EdfScheduler::new(1, 5);
+        scheduler.reserve(0, reservation(2, 5, 5)).unwrap();
+        scheduler.reserve(1, reservation(1, 3, 3)).unwrap();
+        scheduler.push(0);
+        scheduler.push(1);
+        scheduler.push(2);
+        let trace = run(&scheduler, 0, 10);
+        // 1 has the earliest deadline, then 0 runs out of budget,
+        // and the background task 2 runs until a period begins
+        assert_eq!(
+            trace,
+            [Some(1), Some(0), Some(0), Some(1), Some(2), Some(0), Some(1), Some(0), Some(2), Some(1)]
+        );
+        assert_eq!(scheduler.deadline_misses(0), 0);
+        assert_eq!(scheduler.deadline_misses(1), 0);
+    }
+
+    #[test]
+    fn count_misses() {
+        let scheduler = EdfScheduler::new(1, 5);
+        scheduler.reserve(0, reservation(2, 2, 5)).unwrap();
+        scheduler.reserve(1, reservation(2, 2, 5)).unwrap();
+        scheduler.push(0);
+        scheduler.push(1);
+        // only one of them can meet the deadline in each period
+        run(&scheduler, 0, 10);
+        assert_eq!(scheduler.deadline_misses(0) + scheduler.deadline_misses(1), 2);
+    }
+}
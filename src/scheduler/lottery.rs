@@ -0,0 +1,451 @@
+//! Lottery scheduler
+//!
+//! Each task holds some tickets, set by its priority.
+//! A lottery is drawn among the ready tasks, each winning with probability
+//! proportional to its tickets, so CPU time is shared proportionally on average.
+//! It's the randomized counterpart of stride scheduling.
+//!
+//! - Currencies: tickets may be issued in a currency funded with base tickets.
+//!   All active tasks of a currency share its funding in proportion to their tickets,
+//!   so a group of tasks can be given a fixed share however many tasks it has.
+//! - Ticket transfer: a task blocked waiting for another, e.g. joining it,
+//!   lends its tickets to it until it's ready again.
+//! - Compensation: a task using only a fraction `f` of its quantum
+//!   has its tickets inflated by `1 / f` until it wins next time,
+//!   so an interactive task gets its share too.
+
+use super::*;
+use alloc::sync::Arc;
+use alloc::vec;
+
+/// Clones share the same state.
+/// Keep one to manage tickets and currencies after handing the scheduler to a `ThreadPool`.
+#[derive(Clone)]
+pub struct LotteryScheduler {
+    inner: Arc<Mutex<LotterySchedulerInner>>,
+}
+
+struct LotterySchedulerInner {
+    max_time_slice: usize,
+    rng: Rng,
+    infos: Vec<LotteryProcInfo>,
+    /// Ready tasks, in no particular order.
+    ready: Vec<Tid>,
+    /// Funding of each currency in base tickets. Currency 0 is the base one.
+    currencies: Vec<u64>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct LotteryProcInfo {
+    present: bool,
+    tickets: u32,
+    currency: usize,
+    rest_slice: usize,
+    /// The task it lends its tickets to, while blocked.
+    lent_to: Option<Tid>,
+    /// Compensation as (quantum, ticks used): tickets are inflated by `quantum / used`.
+    compensation: Option<(usize, usize)>,
+}
+
+/// Fixed-point scale of ticket values, so currency conversions keep precision.
+///
+/// Values are `u128`: a value in a currency is at most 2^32 tickets × `VALUE_SCALE`
+/// × 2^32 funding, and compensation may inflate it much further.
+const VALUE_SCALE: u128 = 1 << 16;
+
+/// xorshift64* pseudo random number generator
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must be nonzero
+        Rng((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A random number in `0..n`.
+    fn below(&mut self, n: u128) -> u128 {
+        // the bias is negligible for totals far below 2^64, or 2^128 beyond that
+        if n <= u128::from(u64::max_value()) {
+            return u128::from(self.next()) % n;
+        }
+        ((u128::from(self.next()) << 64) | u128::from(self.next())) % n
+    }
+}
+
+impl Scheduler for LotteryScheduler {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+    }
+    fn pop(&self, _cpu_id: usize) -> Option<usize> {
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        self.inner.lock().tick_n(current_tid, 1)
+    }
+    fn tick_n(&self, current_tid: usize, n: usize) -> bool {
+        self.inner.lock().tick_n(current_tid, n)
+    }
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner
+            .lock()
+            .infos
+            .get(current_tid)
+            .map_or(1, |info| info.rest_slice.max(1))
+    }
+    fn lend(&self, from: usize, to: usize) {
+        self.inner.lock().lend(from, to);
+    }
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.set_tickets(tid, u32::from(priority));
+    }
+    fn cal_priority(&self, priority: u8) -> u8 {
+        priority
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn start(&self, _tid: usize) {}
+    fn get_tick(&self, _tid: usize) -> u8 {1}
+    fn end(&self, _tid: usize) {}
+
+    fn set_success(&self, _tid:usize, _value: bool) {}
+    fn get_success(&self, _tid:usize) -> bool {true}
+    fn reset_slice(&self, _tid:usize) {}
+}
+
+impl LotteryScheduler {
+    /// Create a scheduler giving the winner `max_time_slice` ticks, with a fixed seed.
+    pub fn new(max_time_slice: usize) -> Self {
+        Self::with_seed(max_time_slice, 0)
+    }
+
+    /// Create a scheduler drawing lotteries from the random sequence of `seed`.
+    pub fn with_seed(max_time_slice: usize, seed: u64) -> Self {
+        let inner = LotterySchedulerInner {
+            max_time_slice: max_time_slice.max(1),
+            rng: Rng::new(seed),
+            infos: Vec::default(),
+            ready: Vec::new(),
+            currencies: vec![0],
+        };
+        LotteryScheduler {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Set the tickets of a task, in its currency. `set_priority` sets them to the priority.
+    ///
+    /// A task has at least one ticket.
+    pub fn set_tickets(&self, tid: Tid, tickets: u32) {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        inner.infos[tid].tickets = tickets.max(1);
+        trace!("lottery {} tickets = {}", tid, tickets);
+    }
+
+    /// Create a currency funded with `funding` base tickets. Return its id.
+    pub fn add_currency(&self, funding: u32) -> usize {
+        let mut inner = self.inner.lock();
+        inner.currencies.push(u64::from(funding));
+        inner.currencies.len() - 1
+    }
+
+    /// Change the funding of a currency.
+    ///
+    /// Return `false` if there is no such currency, or it's the base one, which can't be funded.
+    pub fn fund_currency(&self, currency: usize, funding: u32) -> bool {
+        let mut inner = self.inner.lock();
+        match inner.currencies.get_mut(currency) {
+            Some(value) if currency != 0 => {
+                *value = u64::from(funding);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Issue the tickets of a task in `currency`. Currency 0 is the base one.
+    ///
+    /// Return `false` if there is no such currency.
+    pub fn set_currency(&self, tid: Tid, currency: usize) -> bool {
+        let mut inner = self.inner.lock();
+        if currency >= inner.currencies.len() {
+            return false;
+        }
+        expand(&mut inner.infos, tid);
+        inner.infos[tid].currency = currency;
+        true
+    }
+}
+
+impl LotterySchedulerInner {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let max_time_slice = self.max_time_slice;
+        let info = &mut self.infos[tid];
+        assert!(!info.present);
+        info.present = true;
+        // ready again, the loan ends
+        info.lent_to = None;
+        let used = max_time_slice - info.rest_slice.min(max_time_slice);
+        if info.rest_slice != 0 && used != 0 {
+            // used only a fraction of its quantum
+            info.compensation = Some((max_time_slice, used));
+        }
+        // not refilled if it didn't run at all, e.g. put back for affinity
+        if info.rest_slice == 0 || used != 0 {
+            info.rest_slice = max_time_slice;
+        }
+        info.tickets = info.tickets.max(1);
+        self.ready.push(tid);
+        trace!("lottery push {}", tid);
+    }
+
+    /// Values of the ready tasks in base tickets, scaled by `VALUE_SCALE`, in the order of `ready`.
+    fn values(&self) -> Vec<u128> {
+        let active = |info: &LotteryProcInfo| info.present || info.lent_to.is_some();
+        // tickets of the active tasks in each currency
+        let mut issued = vec![0u128; self.currencies.len()];
+        for info in self.infos.iter().filter(|info| active(info)) {
+            issued[info.currency] += u128::from(info.tickets);
+        }
+        let value = |info: &LotteryProcInfo| {
+            let tickets = u128::from(info.tickets) * VALUE_SCALE;
+            match info.currency {
+                0 => tickets,
+                currency => tickets * u128::from(self.currencies[currency]) / issued[currency],
+            }
+        };
+        let mut values: Vec<u128> = self
+            .ready
+            .iter()
+            .map(|&tid| {
+                let info = &self.infos[tid];
+                match info.compensation {
+                    // saturated only by an absurd quantum
+                    Some((quantum, used)) => {
+                        value(info).saturating_mul(quantum as u128) / used as u128
+                    }
+                    None => value(info),
+                }
+            })
+            .collect();
+        // add the loans to the ready task at the end of each chain
+        for info in self.infos.iter().filter(|info| !info.present) {
+            let mut to = match info.lent_to {
+                Some(to) => to,
+                None => continue,
+            };
+            // a cycle is a deadlock, the steps are bounded
+            for _ in 0..self.infos.len() {
+                let holder = &self.infos[to];
+                if holder.present {
+                    let index = self.ready.iter().position(|&t| t == to).unwrap();
+                    values[index] = values[index].saturating_add(value(info));
+                    break;
+                }
+                match holder.lent_to {
+                    Some(next) => to = next,
+                    None => break,
+                }
+            }
+        }
+        values
+    }
+
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        // only the allowed tasks take part in the draw
+        let first = self.ready.iter().position(|&tid| allowed(tid))?;
+        let mut values = self.values();
+        for (value, &tid) in values.iter_mut().zip(&self.ready) {
+            if !allowed(tid) {
+                *value = 0;
+            }
+        }
+        let total = values
+            .iter()
+            .fold(0u128, |total, &value| total.saturating_add(value));
+        let index = if total == 0 {
+            // all in currencies without funding
+            first
+        } else {
+            let mut winner = self.rng.below(total);
+            values
+                .iter()
+                .position(|&value| {
+                    if winner < value {
+                        true
+                    } else {
+                        winner -= value;
+                        false
+                    }
+                })
+                .unwrap()
+        };
+        let tid = self.ready.swap_remove(index);
+        let info = &mut self.infos[tid];
+        info.present = false;
+        info.compensation = None;
+        trace!("lottery pop {}", tid);
+        Some(tid)
+    }
+
+    fn tick_n(&mut self, current: Tid, n: usize) -> bool {
+        expand(&mut self.infos, current);
+        let info = &mut self.infos[current];
+        assert!(!info.present);
+        info.rest_slice = info.rest_slice.saturating_sub(n);
+        info.rest_slice == 0
+    }
+
+    fn lend(&mut self, from: Tid, to: Tid) {
+        expand(&mut self.infos, from.max(to));
+        if from != to {
+            self.infos[from].lent_to = Some(to);
+            trace!("lottery {} lends to {}", from, to);
+        }
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        if let Some(index) = self.ready.iter().position(|&t| t == tid) {
+            self.ready.swap_remove(index);
+            self.infos[tid].present = false;
+        }
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+
+    /// Draw `rounds` lotteries among ready tasks running full quanta,
+    /// returning the wins of each task.
+    fn draw(scheduler: &LotteryScheduler, rounds: usize, tasks: usize) -> Vec<usize> {
+        let mut wins = vec![0; tasks];
+        for _ in 0..rounds {
+            let tid = scheduler.pop(0).unwrap();
+            wins[tid] += 1;
+            while !scheduler.tick(tid) {}
+            scheduler.push(tid);
+        }
+        wins
+    }
+
+    #[test]
+    fn proportional_share() {
+        let scheduler = LotteryScheduler::with_seed(1, 42);
+        scheduler.set_priority(0, 1);
+        scheduler.set_priority(1, 3);
+        scheduler.push(0);
+        scheduler.push(1);
+        let wins = draw(&scheduler, 4000, 2);
+        assert!(wins[0] > 850 && wins[0] < 1150, "{:?}", wins);
+        // deterministic for the same seed
+        let again = LotteryScheduler::with_seed(1, 42);
+        again.set_priority(0, 1);
+        again.set_priority(1, 3);
+        again.push(0);
+        again.push(1);
+        assert_eq!(draw(&again, 4000, 2), wins);
+    }
+
+    #[test]
+    fn currency() {
+        let scheduler = LotteryScheduler::with_seed(1, 1);
+        // three tasks sharing a currency worth as much as the fourth task
+        let currency = scheduler.add_currency(10);
+        for tid in 0..3 {
+            assert!(scheduler.set_currency(tid, currency));
+            scheduler.set_tickets(tid, 100);
+        }
+        scheduler.set_tickets(3, 10);
+        assert!(!scheduler.set_currency(3, currency + 1));
+        assert!(!scheduler.fund_currency(0, 10));
+        assert!(!scheduler.fund_currency(currency + 1, 10));
+        assert!(scheduler.fund_currency(currency, 10));
+        for tid in 0..4 {
+            scheduler.push(tid);
+        }
+        let wins = draw(&scheduler, 6000, 4);
+        assert!(wins[3] > 2800 && wins[3] < 3200, "{:?}", wins);
+    }
+
+    #[test]
+    fn ticket_transfer() {
+        let scheduler = LotteryScheduler::with_seed(1, 7);
+        for tid in 0..3 {
+            scheduler.set_tickets(tid, 1);
+            scheduler.push(tid);
+        }
+        // 0 blocks joining 1, which now holds 2 of the 3 tickets
+        scheduler.remove(0);
+        scheduler.lend(0, 1);
+        let wins = draw(&scheduler, 3000, 3);
+        assert_eq!(wins[0], 0);
+        assert!(wins[1] > 1800 && wins[1] < 2200, "{:?}", wins);
+        // the loan ends when 0 is ready again
+        scheduler.push(0);
+        let wins = draw(&scheduler, 3000, 3);
+        assert!(wins[1] > 800 && wins[1] < 1200, "{:?}", wins);
+    }
+
+    #[test]
+    fn compensation() {
+        let scheduler = LotteryScheduler::with_seed(4, 3);
+        scheduler.set_tickets(0, 1);
+        scheduler.set_tickets(1, 1);
+        scheduler.push(0);
+        scheduler.push(1);
+        let mut wins = [0; 2];
+        for _ in 0..4000 {
+            let tid = scheduler.pop(0).unwrap();
+            wins[tid] += 1;
+            if tid == 0 {
+                // uses a quarter of its quantum, then blocks shortly
+                scheduler.tick(0);
+            } else {
+                while !scheduler.tick(1) {}
+            }
+            scheduler.push(tid);
+        }
+        // 0 wins 4 times as often, so both get about the same CPU time
+        assert!(wins[0] > 3000 && wins[0] < 3400, "{:?}", wins);
+    }
+
+    #[test]
+    fn huge_values() {
+        let scheduler = LotteryScheduler::with_seed(4, 5);
+        let currency = scheduler.add_currency(u32::max_value());
+        assert!(scheduler.set_currency(0, currency));
+        scheduler.set_tickets(0, u32::max_value());
+        scheduler.set_tickets(1, u32::max_value());
+        scheduler.push(0);
+        scheduler.push(1);
+        let mut wins = [0; 2];
+        for _ in 0..4000 {
+            let tid = scheduler.pop(0).unwrap();
+            wins[tid] += 1;
+            if tid == 0 {
+                // compensated by 4 on top of the largest funding
+                scheduler.tick(0);
+            } else {
+                while !scheduler.tick(1) {}
+            }
+            scheduler.push(tid);
+        }
+        assert!(wins[0] > 3000 && wins[0] < 3400, "{:?}", wins);
+    }
+}
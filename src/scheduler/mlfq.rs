@@ -0,0 +1,249 @@
+//! Multi-level feedback queue scheduler
+//!
+//! Ready tasks wait in queues of several levels, and the first task of the highest
+//! non-empty level is selected to run. Each level gives a quantum, longer at lower levels.
+//!
+//! A task using up the quantum of its level is demoted, even across several runs,
+//! while a task blocking before that stays at its level.
+//! So interactive tasks stay high, and CPU-bound ones sink.
+//! To prevent starvation, all tasks are periodically boosted to the highest level.
+//!
+//! Priorities are ignored: the level of a task is learned from its behavior.
+
+use super::*;
+
+pub struct MlfqScheduler {
+    inner: Mutex<MlfqSchedulerInner>,
+}
+
+struct MlfqSchedulerInner {
+    /// Quantum of each level in ticks, from the highest.
+    quanta: Vec<usize>,
+    /// Ticks between two boosts.
+    boost_interval: usize,
+    /// Ticks since the last boost.
+    since_boost: usize,
+    infos: Vec<MlfqProcInfo>,
+    queues: Vec<VecDeque<Tid>>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct MlfqProcInfo {
+    present: bool,
+    /// Ever pushed. A new task starts at the highest level.
+    seen: bool,
+    level: usize,
+    /// Ticks left at its level before being demoted.
+    rest_slice: usize,
+}
+
+impl Scheduler for MlfqScheduler {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+    }
+    fn pop(&self, _cpu_id: usize) -> Option<usize> {
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        self.inner.lock().tick(current_tid)
+    }
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner.lock().remaining_slice(current_tid)
+    }
+    fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn cal_priority(&self, priority: u8) -> u8 {
+        priority
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn start(&self, _tid: usize) {}
+    fn get_tick(&self, _tid: usize) -> u8 {1}
+    fn end(&self, _tid: usize) {}
+
+    fn set_success(&self, _tid:usize, _value: bool) {}
+    fn get_success(&self, _tid:usize) -> bool {true}
+    fn reset_slice(&self, _tid:usize) {}
+}
+
+impl MlfqScheduler {
+    /// Create a scheduler of `levels` levels.
+    /// The quantum is `base_quantum` ticks at the highest level, doubled at each lower level.
+    /// All tasks are boosted every `boost_interval` ticks.
+    pub fn new(levels: usize, base_quantum: usize, boost_interval: usize) -> Self {
+        let quanta = (0..levels.max(1))
+            .map(|level| base_quantum.max(1) << level.min(16))
+            .collect();
+        Self::with_quanta(quanta, boost_interval)
+    }
+
+    /// Create a scheduler with the quantum of each level, from the highest.
+    /// All tasks are boosted every `boost_interval` ticks.
+    ///
+    /// The ticks are counted on every CPU running a task.
+    pub fn with_quanta(quanta: Vec<usize>, boost_interval: usize) -> Self {
+        assert!(!quanta.is_empty(), "at least one level is needed");
+        let quanta: Vec<usize> = quanta.into_iter().map(|quantum| quantum.max(1)).collect();
+        let inner = MlfqSchedulerInner {
+            queues: quanta.iter().map(|_| VecDeque::new()).collect(),
+            quanta,
+            boost_interval: boost_interval.max(1),
+            since_boost: 0,
+            infos: Vec::default(),
+        };
+        MlfqScheduler {
+            inner: Mutex::new(inner),
+        }
+    }
+}
+
+impl MlfqSchedulerInner {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        assert!(!info.present);
+        info.present = true;
+        if !info.seen {
+            info.seen = true;
+            info.level = 0;
+            info.rest_slice = self.quanta[0];
+        }
+        self.queues[info.level].push_back(tid);
+        trace!("mlfq push {} at level {}", tid, info.level);
+    }
+
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        let tid = self.queues.iter_mut().find_map(|queue| {
+            let index = queue.iter().position(|&tid| allowed(tid))?;
+            queue.remove(index)
+        })?;
+        self.infos[tid].present = false;
+        trace!("mlfq pop {} at level {}", tid, self.infos[tid].level);
+        Some(tid)
+    }
+
+    fn remaining_slice(&self, current: Tid) -> usize {
+        self.infos
+            .get(current)
+            .map_or(1, |info| info.rest_slice.max(1))
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        self.since_boost += 1;
+        if self.since_boost >= self.boost_interval {
+            self.boost();
+            return true;
+        }
+        let info = &mut self.infos[current];
+        assert!(!info.present);
+        if info.rest_slice > 0 {
+            info.rest_slice -= 1;
+        }
+        if info.rest_slice == 0 {
+            // used up its allotment, demote
+            info.level = (info.level + 1).min(self.quanta.len() - 1);
+            info.rest_slice = self.quanta[info.level];
+            trace!("mlfq demote {} to level {}", current, info.level);
+            return true;
+        }
+        // preempt for a task at a higher level
+        let level = info.level;
+        self.queues[..level].iter().any(|queue| !queue.is_empty())
+    }
+
+    /// Move all tasks to the highest level, keeping the order of the ready ones.
+    fn boost(&mut self) {
+        trace!("mlfq boost");
+        self.since_boost = 0;
+        let (top, lower) = self.queues.split_at_mut(1);
+        for queue in lower {
+            top[0].extend(queue.drain(..));
+        }
+        for info in self.infos.iter_mut() {
+            info.level = 0;
+            info.rest_slice = self.quanta[0];
+        }
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            info.present = false;
+            let queue = &mut self.queues[info.level];
+            if let Some(index) = queue.iter().position(|&t| t == tid) {
+                queue.remove(index);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+
+    fn level(scheduler: &MlfqScheduler, tid: Tid) -> usize {
+        scheduler.inner.lock().infos[tid].level
+    }
+
+    #[test]
+    fn demote_on_used_quantum() {
+        let scheduler = MlfqScheduler::new(3, 2, 1000);
+        scheduler.push(0);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert!(!scheduler.tick(0));
+        assert!(scheduler.tick(0));
+        assert_eq!(level(&scheduler, 0), 1);
+        assert_eq!(scheduler.remaining_slice(0), 4);
+        // an interactive task preempts it at a higher level
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(1));
+    }
+
+    #[test]
+    fn stay_on_early_block() {
+        let scheduler = MlfqScheduler::new(3, 2, 1000);
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert!(!scheduler.tick(0));
+        // 0 blocks, and wakes up later
+        assert_eq!(scheduler.pop(0), Some(1));
+        scheduler.push(0);
+        assert!(!scheduler.tick(1));
+        assert_eq!(level(&scheduler, 0), 0);
+        // its allotment is not refilled, so it can't game the scheduler by blocking
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert!(scheduler.tick(0));
+        assert_eq!(level(&scheduler, 0), 1);
+    }
+
+    #[test]
+    fn boost_all() {
+        let scheduler = MlfqScheduler::new(2, 1, 5);
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert!(scheduler.tick(0));
+        scheduler.push(0);
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert!(scheduler.tick(1));
+        scheduler.push(1);
+        assert_eq!(level(&scheduler, 0), 1);
+        assert_eq!(level(&scheduler, 1), 1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert!(!scheduler.tick(0));
+        // round robin at the lowest level
+        assert!(scheduler.tick(0));
+        assert_eq!(level(&scheduler, 0), 1);
+        assert!(scheduler.tick(0));
+        assert_eq!(level(&scheduler, 0), 0);
+        assert_eq!(level(&scheduler, 1), 0);
+    }
+}
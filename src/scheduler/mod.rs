@@ -3,12 +3,20 @@ use alloc::{collections::BinaryHeap, vec::Vec, collections::VecDeque};
 use log::*;
 use spin::Mutex;
 
+pub use self::cfs::CfsScheduler;
+pub use self::edf::EdfScheduler;
+pub use self::lottery::LotteryScheduler;
+pub use self::mlfq::MlfqScheduler;
 pub use self::o1::O1Scheduler;
 pub use self::rr::RRScheduler;
 pub use self::pt::PTScheduler;
 pub use self::stride::StrideScheduler;
 pub use self::work_stealing::WorkStealingScheduler;
 
+mod cfs;
+mod edf;
+mod lottery;
+mod mlfq;
 mod pt;
 mod o1;
 mod rr;
@@ -18,16 +26,80 @@ mod work_stealing;
 type Tid = usize;
 
 /// The scheduler for a ThreadPool
+///
+/// Threads are identified by the slot index of their `Tid` (without the generation),
+/// so indexes are dense and reused after a thread is removed.
 pub trait Scheduler: 'static {
     /// Push a thread to the back of ready queue.
     fn push(&self, tid: Tid);
     /// Select a thread to run, pop it from the queue.
     fn pop(&self, cpu_id: usize) -> Option<Tid>;
+    /// Like `pop`, but select only among the threads `allowed` returns true for,
+    /// e.g. the ones allowed to run on `cpu_id`. The others are left in place.
+    ///
+    /// `allowed` may be called with the scheduler locked.
+    ///
+    /// The default pops until an allowed thread is found, then pushes the skipped ones back,
+    /// so they lose their places in the queue.
+    fn pop_allowed(&self, cpu_id: usize, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        let mut skipped = Vec::new();
+        let ret = loop {
+            match self.pop(cpu_id) {
+                Some(tid) if !allowed(tid) => skipped.push(tid),
+                ret => break ret,
+            }
+        };
+        for tid in skipped {
+            self.push(tid);
+        }
+        ret
+    }
     /// Got a tick from CPU.
     /// Return true if need reschedule.
     fn tick(&self, current_tid: Tid) -> bool;
+    /// Got `n` ticks from CPU at once, e.g. on a late interrupt in tickless mode.
+    /// Return true if need reschedule on any of them.
+    ///
+    /// The default calls `tick` `n` times.
+    fn tick_n(&self, current_tid: Tid, n: usize) -> bool {
+        (0..n).fold(false, |r, _| self.tick(current_tid) | r)
+    }
+    /// Number of ticks the running thread may go on before `tick` returns true.
+    /// Used to program the next timer interrupt in tickless mode.
+    ///
+    /// The default is 1, i.e. tick periodically.
+    fn remaining_slice(&self, _current_tid: Tid) -> usize {
+        1
+    }
+    /// The current tick of the clock of the `ThreadPool`,
+    /// called on every tick of each CPU and before `pop`. It never goes back.
+    fn set_time(&self, _now: usize) {}
+    /// Set or clear the real-time reservation of a thread.
+    ///
+    /// The default only accepts clearing it, as reservations are not supported.
+    fn reserve(&self, _tid: Tid, reservation: Option<Reservation>) -> Result<(), ReserveError> {
+        match reservation {
+            Some(_) => Err(ReserveError::Unsupported),
+            None => Ok(()),
+        }
+    }
+    /// Number of deadlines the reservation of a thread missed.
+    fn deadline_misses(&self, _tid: Tid) -> usize {
+        0
+    }
+    /// Thread `from` is blocked waiting for thread `to`, e.g. joining it,
+    /// so it may lend its share of CPU to `to`. The loan ends when `from` is pushed again.
+    fn lend(&self, _from: Tid, _to: Tid) {}
     /// Set priority of a thread.
     fn set_priority(&self, tid: Tid, priority: u8);
+    /// Change priority of a thread which may be in the ready queue,
+    /// e.g. boosted by priority inheritance.
+    ///
+    /// The default implementation calls `set_priority`,
+    /// which is fine if the position in the ready queue doesn't depend on priority.
+    fn update_priority(&self, tid: Tid, priority: u8) {
+        self.set_priority(tid, priority);
+    }
     /// calculate priority of a thread.
     fn cal_priority(&self, priority: u8) -> u8;
     /// remove a thread in ready queue.
@@ -46,6 +118,30 @@ pub trait Scheduler: 'static {
     fn reset_slice(&self, tid:Tid);
 }
 
+/// A real-time reservation of a thread, in ticks: in each period of `period` ticks,
+/// it's guaranteed to run for `runtime` ticks within `deadline` ticks from the start of the period.
+///
+/// It should hold that 0 < `runtime` <= `deadline` <= `period`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Reservation {
+    pub runtime: usize,
+    pub deadline: usize,
+    pub period: usize,
+}
+
+/// Errors returned by `Scheduler::reserve`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReserveError {
+    /// The scheduler doesn't support reservations.
+    Unsupported,
+    /// The reservation is not 0 < `runtime` <= `deadline` <= `period`.
+    Invalid,
+    /// Admitting it would exceed the CPU utilization the scheduler can guarantee.
+    Overloaded,
+    /// The thread doesn't exist.
+    NoThread,
+}
+
 fn expand<T: Default + Clone>(vec: &mut Vec<T>, id: usize) {
     let len = vec.len();
     vec.resize(len.max(id + 1), T::default());
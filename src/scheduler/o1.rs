@@ -1,17 +1,196 @@
 //! O(1) scheduler introduced in Linux 2.6
 //!
-//! Two queues are maintained, one is active, another is inactive.
-//! Take the first task from the active queue to run. When it is empty, swap active and inactive queues.
+//! Each of the 140 priorities has a FIFO queue, and a bitmap tells the non-empty ones,
+//! so the first task of the highest priority is found in constant time.
+//! Two such priority arrays are maintained, one is active, another is expired.
+//! A task using up its time slice goes to the expired array.
+//! When the active one is empty, swap active and expired arrays.
+//!
+//! Priorities 0 to 99 are real-time: their tasks run round robin and never expire.
+//! Priorities 100 to 139 are normal: the time slice depends on the static priority,
+//! and the dynamic priority gets a bonus of up to 5 levels from the average sleep time.
+//! An interactive task, sleeping a lot, goes back to the active array
+//! when its time slice is used up, unless the expired tasks are starving.
 
 use super::*;
 
+/// Number of priorities.
+const MAX_PRIO: usize = 140;
+
+/// Priorities below it are real-time.
+const MAX_RT_PRIO: usize = 100;
+
+/// Static priority of nice 0.
+const DEFAULT_PRIO: usize = 120;
+
+/// Upper bound of the average sleep time in ticks, giving the full bonus.
+const MAX_SLEEP_AVG: usize = 100;
+
+/// Levels of priority the bonus may change: from -5 to +5.
+const MAX_BONUS: usize = 10;
+
+/// Time slice of real-time tasks in ticks.
+const RT_TIME_SLICE: usize = 10;
+
+const BITMAP_WORDS: usize = (MAX_PRIO + 63) / 64;
+
 pub struct O1Scheduler {
     inner: Mutex<O1SchedulerInner>,
 }
 
 struct O1SchedulerInner {
-    active_queue: usize,
-    queues: [Vec<Tid>; 2],
+    /// Index of the active array in `arrays`.
+    active: usize,
+    arrays: [PrioArray; 2],
+    infos: Vec<O1ProcInfo>,
+    /// The current tick.
+    now: usize,
+    /// When the first task of the expired array expired. `None` if it's empty.
+    expired_since: Option<usize>,
+}
+
+struct PrioArray {
+    /// Number of tasks in the array.
+    len: usize,
+    /// Non-empty queues, as bits.
+    bitmap: [u64; BITMAP_WORDS],
+    queues: Vec<VecDeque<Tid>>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct O1ProcInfo {
+    present: bool,
+    /// Ever pushed. A new task starts with a full time slice.
+    seen: bool,
+    /// `set_priority` was called. Otherwise it gets the default priority on the first push.
+    prio_set: bool,
+    static_prio: usize,
+    /// The priority of the queue it's in.
+    prio: usize,
+    /// The array it's in.
+    array: usize,
+    time_slice: usize,
+    /// Average sleep time in ticks, from 0 to `MAX_SLEEP_AVG`.
+    sleep_avg: usize,
+    /// The tick it last ran at.
+    last_ran: usize,
+    /// Used up its time slice, and goes to the expired array on push.
+    expired: bool,
+}
+
+impl PrioArray {
+    fn new() -> Self {
+        PrioArray {
+            len: 0,
+            bitmap: [0; BITMAP_WORDS],
+            queues: (0..MAX_PRIO).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    fn push(&mut self, tid: Tid, prio: usize) {
+        self.queues[prio].push_back(tid);
+        self.bitmap[prio / 64] |= 1 << (prio % 64);
+        self.len += 1;
+    }
+
+    /// The highest priority of a queued task.
+    fn first(&self) -> Option<usize> {
+        self.bitmap
+            .iter()
+            .enumerate()
+            .find(|(_, &bits)| bits != 0)
+            .map(|(i, bits)| i * 64 + bits.trailing_zeros() as usize)
+    }
+
+    /// Pop the first allowed task of the highest priority.
+    /// Only the queues with a bit set are searched.
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        let queues = &self.queues;
+        let (prio, index) = self
+            .bitmap
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &bits)| {
+                (0..64)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| i * 64 + bit)
+            })
+            .find_map(|prio| {
+                let index = queues[prio].iter().position(|&tid| allowed(tid))?;
+                Some((prio, index))
+            })?;
+        let tid = self.queues[prio].remove(index).unwrap();
+        self.mark_removed(prio);
+        Some(tid)
+    }
+
+    fn remove(&mut self, tid: Tid, prio: usize) {
+        let queue = &mut self.queues[prio];
+        if let Some(index) = queue.iter().position(|&t| t == tid) {
+            queue.remove(index);
+            self.mark_removed(prio);
+        }
+    }
+
+    fn mark_removed(&mut self, prio: usize) {
+        if self.queues[prio].is_empty() {
+            self.bitmap[prio / 64] &= !(1 << (prio % 64));
+        }
+        self.len -= 1;
+    }
+}
+
+impl O1ProcInfo {
+    fn is_rt(&self) -> bool {
+        self.static_prio < MAX_RT_PRIO
+    }
+
+    /// The bonus from the average sleep time, from 0 to `MAX_BONUS`.
+    fn bonus(&self) -> usize {
+        self.sleep_avg * MAX_BONUS / MAX_SLEEP_AVG
+    }
+
+    /// The priority boosted or penalized by the bonus.
+    fn effective_prio(&self) -> usize {
+        if self.is_rt() {
+            return self.static_prio;
+        }
+        (self.static_prio + MAX_BONUS / 2)
+            .saturating_sub(self.bonus())
+            .max(MAX_RT_PRIO)
+            .min(MAX_PRIO - 1)
+    }
+
+    /// An interactive task sleeps enough to get the bonus of its nice level:
+    /// from 2 levels at nice -20 to 7 at nice 19, where it's not possible.
+    fn is_interactive(&self) -> bool {
+        let nice = self.static_prio as isize - DEFAULT_PRIO as isize;
+        self.bonus() as isize - (MAX_BONUS / 2) as isize >= 2 + nice / 4
+    }
+
+    /// The time slice of its static priority, as Linux does at 100 Hz:
+    /// 10 ticks at nice 0, from 80 ticks at nice -20 to 1 at nice 19.
+    fn base_time_slice(&self) -> usize {
+        if self.is_rt() {
+            RT_TIME_SLICE
+        } else if self.static_prio < DEFAULT_PRIO {
+            (MAX_PRIO - self.static_prio) * 2
+        } else {
+            ((MAX_PRIO - self.static_prio) / 2).max(1)
+        }
+    }
+}
+
+/// Priority 1, the default, is nice 0. Each priority above it is one nice level higher,
+/// up to nice -20 at 21. Priority 0 is nice 1.
+/// Priorities from 22 are real-time, from 99 downwards.
+fn static_prio(priority: u8) -> usize {
+    let priority = priority as usize;
+    if priority <= 21 {
+        DEFAULT_PRIO + 1 - priority
+    } else {
+        (MAX_RT_PRIO - 1).saturating_sub(priority - 22)
+    }
 }
 
 impl Scheduler for O1Scheduler {
@@ -19,15 +198,33 @@ impl Scheduler for O1Scheduler {
         self.inner.lock().push(tid);
     }
     fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
     }
     fn tick(&self, current_tid: usize) -> bool {
         self.inner.lock().tick(current_tid)
     }
-    fn set_priority(&self, _tid: usize, _priority: u8) {}
-    fn cal_priority(&self, _priority: u8) -> u8 {1}
-    fn remove(&self, _tid: usize) {
-        unimplemented!()
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner
+            .lock()
+            .infos
+            .get(current_tid)
+            .map_or(1, |info| info.time_slice.max(1))
+    }
+    fn set_time(&self, now: usize) {
+        let mut inner = self.inner.lock();
+        inner.now = inner.now.max(now);
+    }
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.inner.lock().set_priority(tid, priority);
+    }
+    fn cal_priority(&self, priority: u8) -> u8 {
+        priority
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
     }
     fn start(&self, _tid: usize) {}
     fn get_tick(&self, _tid: usize) -> u8 {1}
@@ -40,8 +237,11 @@ impl Scheduler for O1Scheduler {
 impl O1Scheduler {
     pub fn new() -> Self {
         let inner = O1SchedulerInner {
-            active_queue: 0,
-            queues: [Vec::new(), Vec::new()],
+            active: 0,
+            arrays: [PrioArray::new(), PrioArray::new()],
+            infos: Vec::default(),
+            now: 0,
+            expired_since: None,
         };
         O1Scheduler {
             inner: Mutex::new(inner),
@@ -51,25 +251,232 @@ impl O1Scheduler {
 
 impl O1SchedulerInner {
     fn push(&mut self, tid: Tid) {
-        let inactive_queue = 1 - self.active_queue;
-        self.queues[inactive_queue].push(tid);
-        trace!("o1 push {}", tid - 1);
+        expand(&mut self.infos, tid);
+        let now = self.now;
+        let info = &mut self.infos[tid];
+        assert!(!info.present);
+        info.present = true;
+        if !info.seen {
+            info.seen = true;
+            // 0 is a valid static priority, of the highest real-time one
+            if !info.prio_set {
+                info.static_prio = static_prio(1);
+            }
+            info.time_slice = info.base_time_slice();
+        } else {
+            // credit the time slept since it last ran
+            let slept = now.saturating_sub(info.last_ran);
+            info.sleep_avg = (info.sleep_avg + slept).min(MAX_SLEEP_AVG);
+        }
+        info.last_ran = now;
+        info.prio = info.effective_prio();
+        let array = if info.expired {
+            info.expired = false;
+            if self.expired_since.is_none() {
+                self.expired_since = Some(now);
+            }
+            1 - self.active
+        } else {
+            self.active
+        };
+        info.array = array;
+        self.arrays[array].push(tid, info.prio);
+        trace!("o1 push {} at prio {}", tid, info.prio);
     }
 
-    fn pop(&mut self) -> Option<Tid> {
-        let ret = match self.queues[self.active_queue].pop() {
-            Some(tid) => return Some(tid),
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        if self.arrays[self.active].len == 0 {
+            // active array is empty, swap 'em
+            self.active = 1 - self.active;
+            self.expired_since = None;
+        }
+        let active = self.active;
+        // an expired task runs only if no active one is allowed, without swapping
+        let ret = match self.arrays[active].pop(allowed) {
+            Some(tid) => Some(tid),
             None => {
-                // active queue is empty, swap 'em
-                self.active_queue = 1 - self.active_queue;
-                self.queues[self.active_queue].pop()
+                let ret = self.arrays[1 - active].pop(allowed);
+                if self.arrays[1 - active].len == 0 {
+                    self.expired_since = None;
+                }
+                ret
             }
         };
+        if let Some(tid) = ret {
+            let info = &mut self.infos[tid];
+            info.present = false;
+            info.last_ran = self.now;
+        }
         trace!("o1 pop {:?}", ret);
         ret
     }
 
-    fn tick(&mut self, _current: Tid) -> bool {
-        true
+    /// The expired tasks waited too long, so interactive ones should expire too.
+    fn expired_starving(&self) -> bool {
+        let ready = self.arrays[0].len + self.arrays[1].len;
+        match self.expired_since {
+            Some(since) => self.now.saturating_sub(since) > MAX_SLEEP_AVG * (ready + 1),
+            None => false,
+        }
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        let now = self.now;
+        let starving = self.expired_starving();
+        let first = self.arrays[self.active].first();
+        let info = &mut self.infos[current];
+        assert!(!info.present);
+        info.last_ran = now;
+        info.sleep_avg = info.sleep_avg.saturating_sub(1);
+        if info.time_slice > 0 {
+            info.time_slice -= 1;
+        }
+        if info.time_slice == 0 {
+            info.time_slice = info.base_time_slice();
+            info.expired = !info.is_rt() && (!info.is_interactive() || starving);
+            return true;
+        }
+        // preempt for a task of higher priority
+        first.map_or(false, |prio| prio < info.prio)
+    }
+
+    fn set_priority(&mut self, tid: Tid, priority: u8) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        info.prio_set = true;
+        info.static_prio = static_prio(priority);
+        let old = info.prio;
+        info.prio = info.effective_prio();
+        if info.present {
+            self.arrays[info.array].remove(tid, old);
+            self.arrays[info.array].push(tid, info.prio);
+        }
+        trace!("o1 {} static prio = {}", tid, info.static_prio);
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            info.present = false;
+            self.arrays[info.array].remove(tid, info.prio);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_and_fifo() {
+        let scheduler = O1Scheduler::new();
+        scheduler.set_priority(0, 1);
+        scheduler.set_priority(1, 1);
+        scheduler.set_priority(2, 5);
+        scheduler.set_priority(3, 30);
+        for tid in 0..4 {
+            scheduler.push(tid);
+        }
+        assert_eq!(scheduler.pop(0), Some(3));
+        assert_eq!(scheduler.pop(0), Some(2));
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert_eq!(scheduler.pop(0), None);
+    }
+
+    #[test]
+    fn highest_rt_priority() {
+        let scheduler = O1Scheduler::new();
+        // both static priority 0, which must not be taken for unset
+        scheduler.set_priority(0, 121);
+        scheduler.set_priority(1, 255);
+        scheduler.set_priority(2, 120);
+        for tid in 0..4 {
+            scheduler.push(tid);
+        }
+        assert_eq!(scheduler.inner.lock().infos[0].static_prio, 0);
+        assert_eq!(scheduler.inner.lock().infos[3].static_prio, DEFAULT_PRIO);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert_eq!(scheduler.pop(0), Some(2));
+        assert_eq!(scheduler.pop(0), Some(3));
+    }
+
+    #[test]
+    fn pop_allowed() {
+        let scheduler = O1Scheduler::new();
+        scheduler.set_priority(0, 30);
+        scheduler.set_priority(1, 1);
+        scheduler.set_priority(2, 1);
+        for tid in 0..3 {
+            scheduler.push(tid);
+        }
+        assert_eq!(scheduler.pop_allowed(0, &|tid| tid == 2), Some(2));
+        assert_eq!(scheduler.pop_allowed(0, &|tid| tid == 2), None);
+        // the skipped ones keep their order
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert_eq!(scheduler.pop(0), Some(1));
+    }
+
+    #[test]
+    fn remove_ready() {
+        let scheduler = O1Scheduler::new();
+        for tid in 0..3 {
+            scheduler.set_priority(tid, 1);
+            scheduler.push(tid);
+        }
+        scheduler.remove(1);
+        scheduler.remove(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert_eq!(scheduler.pop(0), Some(2));
+        assert_eq!(scheduler.pop(0), None);
+    }
+
+    #[test]
+    fn hog_expires() {
+        let scheduler = O1Scheduler::new();
+        scheduler.set_priority(0, 1);
+        scheduler.set_priority(1, 1);
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        assert_eq!(scheduler.remaining_slice(0), 10);
+        for now in 1..10 {
+            scheduler.set_time(now);
+            assert!(!scheduler.tick(0));
+        }
+        scheduler.set_time(10);
+        assert!(scheduler.tick(0));
+        // expired behind 1, even though 1 has the same priority
+        scheduler.push(0);
+        assert_eq!(scheduler.pop(0), Some(1));
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(1));
+        assert_eq!(scheduler.pop(0), Some(0));
+    }
+
+    #[test]
+    fn interactive_bonus() {
+        let scheduler = O1Scheduler::new();
+        scheduler.set_priority(0, 1);
+        scheduler.set_priority(1, 1);
+        scheduler.push(0);
+        assert_eq!(scheduler.pop(0), Some(0));
+        // 0 sleeps long, then wakes up with the full bonus
+        scheduler.set_time(200);
+        scheduler.push(0);
+        scheduler.push(1);
+        assert_eq!(scheduler.pop(0), Some(0));
+        let info = scheduler.inner.lock().infos[0];
+        assert_eq!(info.prio, DEFAULT_PRIO - MAX_BONUS / 2);
+        // it's interactive, so it stays active after using up its slice
+        for now in 201..211 {
+            scheduler.set_time(now);
+            scheduler.tick(0);
+        }
+        scheduler.push(0);
+        assert_eq!(scheduler.pop(0), Some(0));
     }
 }
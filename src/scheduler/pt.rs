@@ -25,14 +25,26 @@ impl Scheduler for PTScheduler {
         self.inner.lock().push(tid);
     }
     fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
     }
     fn tick(&self, current_tid: usize) -> bool {
-        self.inner.lock().tick(current_tid)
+        self.inner.lock().tick_n(current_tid, 1)
+    }
+    fn tick_n(&self, current_tid: usize, n: usize) -> bool {
+        self.inner.lock().tick_n(current_tid, n)
+    }
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner.lock().remaining_slice(current_tid)
     }
     fn set_priority(&self, _tid: usize, _priority: u8) {
         self.inner.lock().set_priority(_tid, _priority)
     }
+    fn update_priority(&self, tid: usize, priority: u8) {
+        self.inner.lock().update_priority(tid, priority)
+    }
     fn cal_priority(&self, _priority: u8) -> u8 {
         self.inner.lock().cal_priority(_priority)
     }
@@ -91,7 +103,7 @@ impl PTSchedulerInner {
         }
     }
 
-    fn pop(&mut self) -> Option<Tid> {
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
         info!("in pt pop()");
         self.active_queue = 0;
         // info!("before init ret");
@@ -99,16 +111,11 @@ impl PTSchedulerInner {
         // info!("after init ret");
         for index in (0..5).rev() {
             // info!("index is {}", index);
-            if self.queues[index].len() > 0 {
+            if let Some(pos) = self.queues[index].iter().position(|&tid| allowed(tid - 1)) {
                 self.active_queue = index;
-                ret = match self.queues[index].pop_front() {
-                    Some(tid) => {
-                        info!("pop result is {}", tid);
-                        Some(tid - 1)},
-                    None => {
-                        self.queues[index].pop_front()
-                    }
-                };
+                let tid = self.queues[index].remove(pos).unwrap();
+                info!("pop result is {}", tid);
+                ret = Some(tid - 1);
                 break;
             }
         }
@@ -118,21 +125,26 @@ impl PTSchedulerInner {
         ret
     }
 
-    fn tick(&mut self, current: Tid) -> bool {
+    fn remaining_slice(&self, current: Tid) -> usize {
+        self.infos
+            .get(current + 1)
+            .map_or(1, |info| info.rest_slice.max(1))
+    }
+
+    fn tick_n(&mut self, current: Tid, n: usize) -> bool {
         let current = current + 1;
         expand(&mut self.infos, current);
 
         let info = &mut self.infos[current];
         if info.start_flag {
-            info.tick_num += 1;
+            info.tick_num = info.tick_num.wrapping_add(n as u8);
         }
 
         //let rest = &mut self.infos[current].rest_slice;
-        if info.rest_slice > 0 {
-            info.rest_slice -= 1;
-        } else {
+        if info.rest_slice < n {
             warn!("current process rest_slice = 0, need reschedule")
         }
+        info.rest_slice = info.rest_slice.saturating_sub(n);
         info!("in tick, tid is {}, rest time is {}, tick num is {}", current, info.rest_slice, info.tick_num);
         info.rest_slice == 0
     }
@@ -141,11 +153,8 @@ impl PTSchedulerInner {
         let tid = tid + 1;
         expand(&mut self.infos, tid);
         let info = &mut self.infos[tid];
-        if priority > 5 {
-            info.priority = 4;
-        } else {
-            info.priority = priority - 1;
-        }
+        // priority 0 shares the lowest queue with 1
+        info.priority = priority.max(1).min(5) - 1;
     }
 
     fn cal_priority(&mut self, priority: u8) -> u8 {
@@ -160,18 +169,31 @@ impl PTSchedulerInner {
         self._list_remove(tid + 1);
         //self.infos[tid + 1].present = false;
     }
+
+    /// Move a queued thread to the queue of its new priority.
+    fn update_priority(&mut self, tid: Tid, priority: u8) {
+        let queued = self._list_remove(tid + 1);
+        self.set_priority(tid, priority);
+        if queued {
+            let priority = self.infos[tid + 1].priority;
+            self.queues[priority as usize].push_back(tid + 1);
+        }
+    }
 }
 
 impl PTSchedulerInner {
-    fn _list_remove(&mut self, i: Tid) {
+    /// Return whether `i` was in the queue.
+    fn _list_remove(&mut self, i: Tid) -> bool {
+        expand(&mut self.infos, i);
         let info = &mut self.infos[i];
         let priority = info.priority as usize;
         for index in 0..self.queues[priority].len() {
             if self.queues[priority][index] == i {
                 self.queues[priority].remove(index);
-                break;
+                return true;
             }
         }
+        false
     }
 }
 
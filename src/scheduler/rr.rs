@@ -26,10 +26,19 @@ impl Scheduler for RRScheduler {
         self.inner.lock().push(tid);
     }
     fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
     }
     fn tick(&self, current_tid: usize) -> bool {
-        self.inner.lock().tick(current_tid)
+        self.inner.lock().tick_n(current_tid, 1)
+    }
+    fn tick_n(&self, current_tid: usize, n: usize) -> bool {
+        self.inner.lock().tick_n(current_tid, n)
+    }
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner.lock().remaining_slice(current_tid)
     }
     fn set_priority(&self, _tid: usize, _priority: u8) {
         self.inner.lock().set_priority(_tid, _priority)
@@ -109,8 +118,13 @@ impl RRSchedulerInner {
     //     trace!("rr push {}", tid - 1);
     // }
 
-    fn pop(&mut self) -> Option<Tid> {
-        let ret = match self.infos[0].next {
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        // the list head is allocated on the first push
+        let mut tid = self.infos.get(0).map_or(0, |head| head.next);
+        while tid != 0 && !allowed(tid - 1) {
+            tid = self.infos[tid].next;
+        }
+        let ret = match tid {
             0 => None,
             tid => {
                 self.infos[tid].present = false;
@@ -122,22 +136,27 @@ impl RRSchedulerInner {
         ret
     }
 
-    fn tick(&mut self, current: Tid) -> bool {
+    fn remaining_slice(&self, current: Tid) -> usize {
+        self.infos
+            .get(current + 1)
+            .map_or(1, |info| info.rest_slice.max(1))
+    }
+
+    fn tick_n(&mut self, current: Tid, n: usize) -> bool {
         let current = current + 1;
         expand(&mut self.infos, current);
         assert!(!self.infos[current].present);
 
         let info = &mut self.infos[current];
         if info.start_flag {
-            info.tick_num += 1;
+            info.tick_num = info.tick_num.wrapping_add(n as u8);
         }
 
         let rest = &mut self.infos[current].rest_slice;
-        if *rest > 0 {
-            *rest -= 1;
-        } else {
+        if *rest < n {
             warn!("current process rest_slice = 0, need reschedule")
         }
+        *rest = rest.saturating_sub(n);
         *rest == 0
     }
 
@@ -67,10 +67,19 @@ impl Scheduler for StrideScheduler {
         self.inner.lock().push(tid);
     }
     fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+        self.inner.lock().pop(&|_| true)
+    }
+    fn pop_allowed(&self, _cpu_id: usize, allowed: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.inner.lock().pop(allowed)
     }
     fn tick(&self, current_tid: usize) -> bool {
-        self.inner.lock().tick(current_tid)
+        self.inner.lock().tick_n(current_tid, 1)
+    }
+    fn tick_n(&self, current_tid: usize, n: usize) -> bool {
+        self.inner.lock().tick_n(current_tid, n)
+    }
+    fn remaining_slice(&self, current_tid: usize) -> usize {
+        self.inner.lock().remaining_slice(current_tid)
     }
     fn set_priority(&self, tid: usize, priority: u8) {
         self.inner.lock().set_priority(tid, priority);
@@ -115,13 +124,23 @@ impl StrideSchedulerInner {
         trace!("stride push {}", tid);
     }
 
-    fn pop(&mut self) -> Option<Tid> {
-        let ret = self.queue.pop().map(|Reverse((_, tid))| tid);
+    fn pop(&mut self, allowed: &dyn Fn(Tid) -> bool) -> Option<Tid> {
+        // entries of threads not allowed, put back as they are
+        let mut skipped = Vec::new();
+        let mut ret = None;
+        while let Some(Reverse((stride, tid))) = self.queue.pop() {
+            if !self.infos[tid].present {
+                continue;
+            }
+            if allowed(tid) {
+                ret = Some(tid);
+                break;
+            }
+            skipped.push(Reverse((stride, tid)));
+        }
+        self.queue.extend(skipped);
         if let Some(tid) = ret {
             let info = &mut self.infos[tid];
-            if !info.present {
-                return self.pop();
-            }
             let old_stride = info.stride;
             info.pass();
             let stride = info.stride;
@@ -132,20 +151,26 @@ impl StrideSchedulerInner {
         ret
     }
 
-    fn tick(&mut self, current: Tid) -> bool {
+    fn remaining_slice(&self, current: Tid) -> usize {
+        self.infos
+            .get(current)
+            .map_or(1, |info| info.rest_slice.max(1))
+    }
+
+    fn tick_n(&mut self, current: Tid, n: usize) -> bool {
         expand(&mut self.infos, current);
         assert!(!self.infos[current].present);
 
         let rest = &mut self.infos[current].rest_slice;
-        if *rest > 0 {
-            *rest -= 1;
-        } else {
+        if *rest < n {
             warn!("current process rest_slice = 0, need reschedule")
         }
+        *rest = rest.saturating_sub(n);
         *rest == 0
     }
 
     fn set_priority(&mut self, tid: Tid, priority: u8) {
+        expand(&mut self.infos, tid);
         self.infos[tid].priority = priority;
         trace!("stride {} priority = {}", tid, priority);
     }
@@ -5,12 +5,20 @@
 //! You need to implement the following functions before use:
 //! - `processor`: Get a reference of the current `Processor`
 //! - `new_kernel_context`: Construct a `Context` of the new kernel thread
+//!
+//! Optionally, implement `new_kernel_context_with_stack` to honor `Builder::stack_size`.
 
 use crate::interrupt::no_interrupt;
 use crate::processor::*;
 use crate::thread_pool::*;
 use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
 use core::marker::PhantomData;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::panic::PanicInfo;
 use core::time::Duration;
 use log::*;
 
@@ -24,12 +32,14 @@ extern "C" {
 #[linkage = "weak"]
 #[no_mangle]
 /// Get a reference of the current `Processor`
-fn processor() -> &'static Processor {
+pub(crate) fn processor() -> &'static Processor {
     #[cfg(target_os = "uefi")]
     unsafe {
         _processor()
     }
-    #[cfg(not(target_os = "uefi"))]
+    #[cfg(all(test, feature = "userland"))]
+    return crate::test_util::processor();
+    #[cfg(not(any(target_os = "uefi", all(test, feature = "userland"))))]
     unimplemented!("thread: Please implement and export `processor`")
 }
 
@@ -41,10 +51,26 @@ fn new_kernel_context(_entry: extern "C" fn(usize) -> !, _arg: usize) -> Box<dyn
     unsafe {
         _new_kernel_context(_entry, _arg)
     }
-    #[cfg(not(target_os = "uefi"))]
+    #[cfg(all(test, feature = "userland"))]
+    return crate::test_util::new_kernel_context(_entry, _arg);
+    #[cfg(not(any(target_os = "uefi", all(test, feature = "userland"))))]
     unimplemented!("thread: Please implement and export `new_kernel_context`")
 }
 
+#[linkage = "weak"]
+#[no_mangle]
+/// Construct a `Context` of the new kernel thread with a stack of `stack_size` bytes.
+/// `stack_size` == 0 means the default size.
+///
+/// The default implementation ignores `stack_size` and calls `new_kernel_context`.
+fn new_kernel_context_with_stack(
+    entry: extern "C" fn(usize) -> !,
+    arg: usize,
+    _stack_size: usize,
+) -> Box<dyn Context> {
+    new_kernel_context(entry, arg)
+}
+
 /// Gets a handle to the thread that invokes it.
 pub fn current() -> Thread {
     Thread {
@@ -52,21 +78,133 @@ pub fn current() -> Thread {
     }
 }
 
-/// Puts the current thread to sleep for the specified amount of time.
+/// Puts the current thread to sleep for at least the specified amount of time.
+///
+/// It's measured in timer ticks, see `ThreadPool::with_tick_rate`.
+/// `dur` is rounded up to ticks, plus one more tick, as the current time is only known
+/// to the tick: the current one may be almost over.
 pub fn sleep(dur: Duration) {
-    let time = dur_to_ticks(dur);
-    trace!("sleep: {:?} ticks", time);
-    processor().manager().sleep(current().id(), time);
-    park();
+    if dur == Duration::from_secs(0) {
+        return;
+    }
+    let tick = processor().manager().ticks_to_dur(1);
+    let deadline = Instant::now()
+        .checked_add(dur)
+        .and_then(|deadline| deadline.checked_add(tick));
+    match deadline {
+        Some(deadline) => sleep_until(deadline),
+        // never reached
+        None => loop {
+            sleep_ticks(0);
+        },
+    }
+}
+
+/// Puts the current thread to sleep until the specified deadline has passed.
+///
+/// The deadline is on the clock of `Instant`, which only advances on timer ticks.
+pub fn sleep_until(deadline: Instant) {
+    // may be woken up early by others, sleep again
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        sleep_ticks(dur_to_ticks(deadline - now));
+    }
+}
+
+/// Sleep for `ticks` ticks. 0 means sleep until woken up.
+fn sleep_ticks(ticks: usize) {
+    trace!("sleep: {:?} ticks", ticks);
+    processor().manager().sleep(current().id(), ticks);
+    yield_now();
+}
+
+/// Convert a duration to timer ticks, rounding up.
+pub(crate) fn dur_to_ticks(dur: Duration) -> usize {
+    processor().manager().dur_to_ticks(dur)
+}
+
+/// A measurement of the monotonic clock of the `ThreadPool`, like `std::time::Instant`.
+///
+/// Its resolution is a timer tick.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    /// Returns an instant corresponding to "now".
+    pub fn now() -> Instant {
+        Instant(processor().manager().now())
+    }
+
+    /// Returns the amount of time elapsed from another instant to this one,
+    /// or zero duration if that instant is later than this one.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
 
-    fn dur_to_ticks(dur: Duration) -> usize {
-        return dur.as_secs() as usize * 100 + dur.subsec_nanos() as usize / 10_000_000;
+    /// Returns the amount of time elapsed from another instant to this one,
+    /// or `None` if that instant is later than this one.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// Returns the amount of time elapsed since this instant was created.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self + duration`, or `None` on overflow.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration).map(Instant)
+    }
+
+    /// Returns `Some(t)` where `t` is the time `self - duration`,
+    /// or `None` if it's before the clock started.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, other: Duration) -> Instant {
+        self.checked_add(other)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, other: Duration) {
+        *self = *self + other;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, other: Duration) -> Instant {
+        self.checked_sub(other)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, other: Duration) {
+        *self = *self - other;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, other: Instant) -> Duration {
+        self.duration_since(other)
     }
 }
 
 // Get the current thread priority
 pub fn get_pri() -> u8{
-    processor().manager().get_pri(current().id())
+    processor().manager().get_pri(current().id()).unwrap()
 }
 
 pub fn start() {
@@ -74,7 +212,7 @@ pub fn start() {
 }
 
 pub fn get_tick() -> u8 {
-    processor().manager().get_tick(current().id())
+    processor().manager().get_tick(current().id()).unwrap()
 }
 
 pub fn end() {
@@ -86,7 +224,7 @@ pub fn set_success(tid: usize, value: bool) {
 }
 
 pub fn get_success() -> bool {
-    processor().manager().get_success(current().id())
+    processor().manager().get_success(current().id()).unwrap()
 }
 
 pub fn reset_slice() {
@@ -96,59 +234,16 @@ pub fn reset_slice() {
 ///
 /// `F`: Type of the function `f`
 /// `T`: Type of the return value of `f`
+///
+/// Panics if the thread table is full. Use `Builder::spawn` to handle it.
 pub fn spawn<F, T>(f: F) -> JoinHandle<T>
 where
     F: Send + 'static + FnOnce() -> T,
     T: Send + 'static,
 {
-    info!("info spawn:");
-    trace!("spawn:");
-
-    // 注意到下面的问题：
-    // Processor只能从入口地址entry+参数arg创建新线程
-    // 而我们现在需要让它执行一个未知类型的（闭包）函数f
-
-    // 首先把函数本体（代码数据）置于堆空间中
-    let f = Box::into_raw(Box::new(f));
-
-    // 定义一个静态函数作为新线程的入口点
-    // 其参数是函数f在堆上的指针
-    // 这样我们就把函数f传到了一个静态函数内部
-    //
-    // 注意到它具有泛型参数，因此对每一次spawn调用，
-    // 由于F类型是独特的，因此都会生成一个新的kernel_thread_entry
-    extern "C" fn kernel_thread_entry<F, T>(f: usize) -> !
-    where
-        F: Send + 'static + FnOnce() -> T,
-        T: Send + 'static,
-    {
-        // 在静态函数内部：
-        // 根据传进来的指针，恢复f
-        let f = unsafe { Box::from_raw(f as *mut F) };
-        // 调用f，并将其返回值也放在堆上
-        let ret = Box::new(f());
-        // 让Processor退出当前线程
-        // 把f返回值在堆上的指针，以线程返回码的形式传递出去
-        let exit_code = Box::into_raw(ret) as usize;
-        processor().manager().exit(current().id(), exit_code);
-        yield_now();
-        // 再也不会被调度回来了
-        unreachable!()
-    }
-
-    // 在Processor中创建新的线程
-    let context = new_kernel_context(kernel_thread_entry::<F, T>, f as usize);
-    let tid = processor().manager().add_pri(context, 1);
-
-    // 接下来看看`JoinHandle::join()`的实现
-    // 了解是如何获取f返回值的
-    return JoinHandle {
-        thread: Thread { tid },
-        mark: PhantomData,
-    };
+    Builder::new().spawn(f).expect("failed to spawn thread")
 }
 
-
 /// Spawns a new thread, returning a JoinHandle for it.
 ///
 /// `F`: Type of the function `f`
@@ -159,50 +254,196 @@ where
     F: Send + 'static + FnOnce() -> T,
     T: Send + 'static,
 {
-    trace!("spawn:");
-
-    // 注意到下面的问题：
-    // Processor只能从入口地址entry+参数arg创建新线程
-    // 而我们现在需要让它执行一个未知类型的（闭包）函数f
-
-    // 首先把函数本体（代码数据）置于堆空间中
-    let f = Box::into_raw(Box::new(f));
-
-    // 定义一个静态函数作为新线程的入口点
-    // 其参数是函数f在堆上的指针
-    // 这样我们就把函数f传到了一个静态函数内部
-    //
-    // 注意到它具有泛型参数，因此对每一次spawn调用，
-    // 由于F类型是独特的，因此都会生成一个新的kernel_thread_entry
-    extern "C" fn kernel_thread_entry<F, T>(f: usize) -> !
+    Builder::new()
+        .priority(priority)
+        .spawn(f)
+        .expect("failed to spawn thread")
+}
+
+/// Thread factory, which can be used in order to configure the properties of a new thread.
+///
+/// ```ignore
+/// let handle = Builder::new()
+///     .name("worker".into())
+///     .priority(3)
+///     .affinity(0b10)
+///     .spawn(|| 42)?;
+/// ```
+pub struct Builder {
+    attr: ThreadAttr,
+    stack_size: usize,
+}
+
+impl Builder {
+    /// Generates the base configuration for spawning a thread.
+    pub fn new() -> Builder {
+        Builder {
+            attr: ThreadAttr::default(),
+            stack_size: 0,
+        }
+    }
+
+    /// Names the thread-to-be. The name is shown in trace logs.
+    pub fn name(mut self, name: String) -> Builder {
+        self.attr.name = Some(name);
+        self
+    }
+
+    /// Sets the size of the stack (in bytes) for the new thread.
+    ///
+    /// Passed to `new_kernel_context_with_stack`. 0 means the default size.
+    pub fn stack_size(mut self, size: usize) -> Builder {
+        self.stack_size = size;
+        self
+    }
+
+    /// Sets the priority of the new thread. Default to 1.
+    pub fn priority(mut self, priority: u8) -> Builder {
+        self.attr.priority = priority;
+        self
+    }
+
+    /// Sets the CPUs the new thread may run on, as a bit mask.
+    /// Default to 0, meaning all CPUs.
+    pub fn affinity(mut self, mask: usize) -> Builder {
+        self.attr.affinity = mask;
+        self
+    }
+
+    /// Whether to detach the new thread, releasing its resources on exit.
+    ///
+    /// The returned `JoinHandle` of a detached thread can't get its result:
+    /// `join` returns `Err(JoinError::Detached)` once the thread has exited.
+    pub fn detached(mut self, detached: bool) -> Builder {
+        self.attr.detached = detached;
+        self
+    }
+
+    /// Spawns a new thread by taking ownership of the `Builder`,
+    /// and returns a `Result` to its `JoinHandle`.
+    ///
+    /// Return `Err(ThreadError::Exhausted)` if the thread table is full,
+    /// instead of panicking like `spawn`.
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, ThreadError>
     where
         F: Send + 'static + FnOnce() -> T,
         T: Send + 'static,
     {
-        // 在静态函数内部：
-        // 根据传进来的指针，恢复f
-        let f = unsafe { Box::from_raw(f as *mut F) };
-        // 调用f，并将其返回值也放在堆上
-        let ret = Box::new(f());
-        // 让Processor退出当前线程
-        // 把f返回值在堆上的指针，以线程返回码的形式传递出去
-        let exit_code = Box::into_raw(ret) as usize;
-        processor().manager().exit(current().id(), exit_code);
-        yield_now();
-        // 再也不会被调度回来了
-        unreachable!()
+        let detached = self.attr.detached;
+        let tid = unsafe { self.spawn_unchecked(f)? };
+        // 接下来看看`JoinHandle::join()`的实现
+        // 了解是如何获取f返回值的
+        Ok(JoinHandle {
+            thread: Thread { tid },
+            detached,
+            mark: PhantomData,
+        })
     }
 
-    // 在Processor中创建新的线程
-    let context = new_kernel_context(kernel_thread_entry::<F, T>, f as usize);
-    let tid = processor().manager().add_pri(context, priority);
+    /// Spawns a new scoped thread using the settings set through this `Builder`.
+    ///
+    /// The thread is never detached, `scope` joins it if its handle doesn't.
+    pub fn spawn_scoped<'scope, 'env, F, T>(
+        mut self,
+        scope: &'scope Scope<'scope, 'env>,
+        f: F,
+    ) -> Result<ScopedJoinHandle<'scope, T>, ThreadError>
+    where
+        F: Send + 'scope + FnOnce() -> T,
+        T: Send + 'scope,
+    {
+        self.attr.detached = false;
+        // `scope` joins the thread before anything it borrows goes away
+        let tid = unsafe { self.spawn_unchecked(f)? };
+        no_interrupt(|| scope.threads.lock().push((tid, drop_result::<T>)));
+        Ok(ScopedJoinHandle {
+            thread: Thread { tid },
+            threads: &scope.threads,
+            mark: PhantomData,
+        })
+    }
 
-    // 接下来看看`JoinHandle::join()`的实现
-    // 了解是如何获取f返回值的
-    return JoinHandle {
-        thread: Thread { tid },
-        mark: PhantomData,
-    };
+    /// Spawns a new thread running `f`, and returns its tid.
+    ///
+    /// Unsafe because `f` and its result may not be `'static`:
+    /// the caller must join the thread before they go away.
+    unsafe fn spawn_unchecked<F, T>(self, f: F) -> Result<Tid, ThreadError>
+    where
+        F: Send + FnOnce() -> T,
+        T: Send,
+    {
+        trace!("spawn: {:?}", self.attr.name);
+
+        // 注意到下面的问题：
+        // Processor只能从入口地址entry+参数arg创建新线程
+        // 而我们现在需要让它执行一个未知类型的（闭包）函数f
+
+        // 首先把函数本体（代码数据）置于堆空间中
+        let f = Box::into_raw(Box::new(f));
+
+        // 定义一个静态函数作为新线程的入口点
+        // 其参数是函数f在堆上的指针
+        // 这样我们就把函数f传到了一个静态函数内部
+        //
+        // 注意到它具有泛型参数，因此对每一次spawn调用，
+        // 由于F类型是独特的，因此都会生成一个新的kernel_thread_entry
+        extern "C" fn kernel_thread_entry<F, T>(f: usize) -> !
+        where
+            F: Send + FnOnce() -> T,
+            T: Send,
+        {
+            // 在静态函数内部：
+            // 根据传进来的指针，恢复f
+            let f = unsafe { Box::from_raw(f as *mut F) };
+            // killed before it starts
+            no_interrupt(|| processor().test_cancel());
+            // 调用f，并将其返回值也放在堆上
+            let ret = Box::new(f());
+            // 让Processor退出当前线程
+            // 把f返回值在堆上的指针，以线程返回码的形式传递出去
+            let exit_code = Box::into_raw(ret) as usize;
+            processor().manager().exit(current().id(), exit_code);
+            yield_now();
+            // 再也不会被调度回来了
+            unreachable!()
+        }
+
+        // 在Processor中创建新的线程
+        let context =
+            new_kernel_context_with_stack(kernel_thread_entry::<F, T>, f as usize, self.stack_size);
+        processor()
+            .manager()
+            .try_add_with(context, self.attr)
+            .map_err(|e| {
+                // 线程没有创建成功，回收函数f
+                drop(Box::from_raw(f));
+                e
+            })
+    }
+}
+
+/// Drop the result of a thread returning `T`, passed by its exit code.
+fn drop_result<T>(exit_code: usize) {
+    drop(unsafe { Box::from_raw(exit_code as *mut T) });
+}
+
+/// Wait for thread `tid` to exit, then remove it and return its exit code.
+fn join_thread(tid: Tid) -> Result<usize, JoinError> {
+    loop {
+        trace!("try to join thread {}", tid);
+        match processor().manager().try_join(tid) {
+            Some(Ok(exit_code)) => return Ok(exit_code),
+            Some(Err(Abort::Panicked(message))) => return Err(JoinError::Panicked(message)),
+            Some(Err(Abort::Killed)) => return Err(JoinError::Cancelled),
+            None => {}
+        }
+        if processor().manager().status(tid).is_none() {
+            // released by itself on exit
+            return Err(JoinError::Detached);
+        }
+        processor().manager().wait(current().id(), tid);
+        yield_now();
+    }
 }
 
 /// Cooperatively gives up a time slice to the OS scheduler.
@@ -210,23 +451,98 @@ pub fn yield_now() {
     trace!("yield:");
     no_interrupt(|| {
         processor().yield_now();
+        // may be killed while switched out, maybe on another CPU now
+        processor().test_cancel();
     });
 }
 
+/// Terminate the current thread because of a panic, instead of the whole kernel.
+///
+/// Call it from the `#[panic_handler]`. If the panic happened on a thread,
+/// the thread exits and its `JoinHandle::join` returns `Err(JoinError::Panicked)`
+/// with the panic message. In this case it never returns.
+///
+/// It returns if there is no current thread (e.g. in the scheduler loop),
+/// if the thread is in a `scope` whose threads may borrow its stack,
+/// on a panic raised while handling a previous one,
+/// or if the thread is locked in the `ThreadPool`, e.g. the panic happened in it.
+/// The panic handler should go on to halt then.
+///
+/// The stack of the thread is not unwound: its destructors don't run,
+/// and the locks it holds are never released.
+///
+/// ```ignore
+/// #[panic_handler]
+/// fn panic(info: &PanicInfo) -> ! {
+///     println!("{}", info);
+///     std_thread::terminate_current_on_panic(info);
+///     loop {}
+/// }
+/// ```
+pub fn terminate_current_on_panic(info: &PanicInfo) {
+    let tid = match processor().tid_option() {
+        Some(tid) => tid,
+        None => return,
+    };
+    if !processor().manager().exit_on_panic(tid, info.to_string()) {
+        return;
+    }
+    yield_now();
+    unreachable!()
+}
+
+/// Whether the current thread is cancelled by `JoinHandle::cancel` or `ThreadPool::kill`.
+///
+/// A thread exits at its next cancellation point on its own.
+/// Long-running work in `no_cancel` may poll it to give up early.
+pub fn is_cancelled() -> bool {
+    no_interrupt(|| processor().manager().is_killed(current().id()))
+}
+
+/// Runs `f` with the cancellation of the current thread deferred until it returns.
+///
+/// The locks of `sync` do it for the time they are held.
+pub fn no_cancel<R>(f: impl FnOnce() -> R) -> R {
+    let tid = current().id();
+    no_interrupt(|| processor().manager().disable_cancel(tid));
+    let ret = f();
+    no_interrupt(|| processor().manager().enable_cancel(tid));
+    ret
+}
+
 /// Blocks unless or until the current thread's token is made available.
+///
+/// Like `std::thread::park`, it may also return spuriously (e.g. on `JoinHandle::cancel`).
 pub fn park() {
-    trace!("park:");
-    processor().manager().sleep(current().id(), 0);
-    yield_now();
+    park_action(|| {});
 }
 
 /// Blocks unless or until the current thread's token is made available.
-/// Calls `f` before thread yields. Can be used to avoid racing.
+/// Calls `f` before thread yields.
 pub fn park_action(f: impl FnOnce()) {
     trace!("park:");
-    processor().manager().sleep(current().id(), 0);
+    let tid = current().id();
+    let parked = no_interrupt(|| processor().manager().park(tid, 0));
     f();
+    if parked {
+        yield_now();
+        no_interrupt(|| processor().manager().end_park(tid));
+    }
+}
+
+/// Blocks unless or until the current thread's token is made available
+/// or the specified duration has been reached.
+///
+/// Return `true` if it timed out, or woke up spuriously.
+pub fn park_timeout(dur: Duration) -> bool {
+    let time = dur_to_ticks(dur).max(1);
+    trace!("park_timeout: {:?} ticks", time);
+    let tid = current().id();
+    if !no_interrupt(|| processor().manager().park(tid, time)) {
+        return false;
+    }
     yield_now();
+    !no_interrupt(|| processor().manager().end_park(tid))
 }
 
 /// A handle to a thread.
@@ -237,17 +553,25 @@ pub struct Thread {
 impl Thread {
     /// Atomically makes the handle's token available if it is not already.
     pub fn unpark(&self) {
-        processor().manager().wakeup(self.tid);
+        no_interrupt(|| processor().manager().unpark(self.tid));
     }
     /// Gets the thread's unique identifier.
     pub fn id(&self) -> usize {
         self.tid
     }
+    /// Gets the thread's name.
+    ///
+    /// Returns a copy, since the name lives in the `ThreadPool`.
+    pub fn name(&self) -> Option<String> {
+        processor().manager().name(self.tid)
+    }
 }
 
 /// An owned permission to join on a thread (block on its termination).
 pub struct JoinHandle<T> {
     thread: Thread,
+    /// Spawned detached, see `Builder::detached`.
+    detached: bool,
     mark: PhantomData<T>,
 }
 
@@ -257,23 +581,333 @@ impl<T> JoinHandle<T> {
         &self.thread
     }
     /// Waits for the associated thread to finish.
-    pub fn join(self) -> Result<T, ()> {
-        loop {
-            trace!("try to join thread {}", self.thread.tid);
-            if let Some(exit_code) = processor().manager().try_remove(self.thread.tid) {
-                // Do not call drop function
-                core::mem::forget(self);
-                // Find return value on the heap from the exit code.
-                return Ok(unsafe { *Box::from_raw(exit_code as *mut T) });
-            }
-            processor().manager().wait(current().id(), self.thread.tid);
-            yield_now();
+    ///
+    /// Return `Err` if the thread panicked or was cancelled,
+    /// or it was detached so its result is gone.
+    pub fn join(self) -> Result<T, JoinError> {
+        let ret = join_thread(self.thread.tid);
+        // Do not call drop function
+        core::mem::forget(self);
+        // Find return value on the heap from the exit code.
+        ret.map(|exit_code| unsafe { *Box::from_raw(exit_code as *mut T) })
+    }
+
+    /// Requests the thread to be cancelled. Join it to wait for its exit.
+    ///
+    /// The thread exits at its next cancellation point, see `ThreadPool::kill`:
+    /// it's interrupted if sleeping or blocked, but never while holding a lock of `sync`.
+    /// Its stack is not unwound, so the destructors on it don't run.
+    ///
+    /// Return `false` if it has already exited.
+    pub fn cancel(&self) -> bool {
+        no_interrupt(|| processor().manager().kill(self.thread.tid))
+    }
+}
+
+/// An error returned by `JoinHandle::join`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum JoinError {
+    /// The thread panicked, with the panic message.
+    Panicked(String),
+    /// The thread was cancelled.
+    Cancelled,
+    /// The thread was detached, so its result is gone.
+    Detached,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(message) => write!(f, "thread {}", message),
+            JoinError::Cancelled => f.write_str("thread was cancelled"),
+            JoinError::Detached => f.write_str("thread was detached"),
         }
     }
 }
 
 impl<T> Drop for JoinHandle<T> {
     fn drop(&mut self) {
-        processor().manager().detach(self.thread.tid);
+        if !self.detached {
+            processor().manager().detach(self.thread.tid);
+        }
+    }
+}
+
+/// Creates a scope for spawning scoped threads.
+///
+/// Unlike `spawn`, the threads spawned by `Scope::spawn` can borrow non-`'static` data,
+/// since all of them are joined before `scope` returns.
+///
+/// If any of them panicked and was not joined by its handle, `scope` panics afterwards.
+///
+/// The current thread can't be cancelled inside the scope, and its panics are not caught
+/// by `terminate_current_on_panic`, since the threads may borrow its stack.
+///
+/// ```ignore
+/// let mut a = vec![1, 2, 3];
+/// let x = 0;
+/// std_thread::scope(|s| {
+///     s.spawn(|| println!("{:?} {}", a, x));
+///     s.spawn(|| println!("{}", x));
+/// });
+/// a.push(4);
+/// ```
+pub fn scope<'env, F, T>(f: F) -> T
+where
+    F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+{
+    let scope = Scope {
+        threads: spin::Mutex::new(Vec::new()),
+        scope: PhantomData,
+        env: PhantomData,
+    };
+    let tid = current().id();
+    no_interrupt(|| processor().manager().enter_scope(tid));
+    let (ret, panicked) = no_cancel(|| {
+        let ret = f(&scope);
+        // join the threads not joined by their handles
+        let mut panicked = false;
+        while let Some((tid, drop_result)) = no_interrupt(|| scope.threads.lock().pop()) {
+            match join_thread(tid) {
+                Ok(exit_code) => drop_result(exit_code),
+                Err(JoinError::Panicked(_)) => panicked = true,
+                Err(_) => {}
+            }
+        }
+        (ret, panicked)
+    });
+    no_interrupt(|| processor().manager().leave_scope(tid));
+    if panicked {
+        panic!("a scoped thread panicked");
+    }
+    ret
+}
+
+/// A scope to spawn scoped threads in. See `scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    /// Threads not joined by their handles yet,
+    /// with the destructor of their results.
+    threads: spin::Mutex<Vec<(Tid, fn(usize))>>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Spawns a new thread within the scope, returning a `ScopedJoinHandle` for it.
+    ///
+    /// Panics if the thread table is full. Use `Builder::spawn_scoped` to handle it.
+    pub fn spawn<F, T>(&'scope self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: Send + 'scope + FnOnce() -> T,
+        T: Send + 'scope,
+    {
+        Builder::new()
+            .spawn_scoped(self, f)
+            .expect("failed to spawn thread")
+    }
+}
+
+/// An owned permission to join on a scoped thread (block on its termination).
+///
+/// Dropping it doesn't detach the thread: `scope` joins it instead.
+pub struct ScopedJoinHandle<'scope, T> {
+    thread: Thread,
+    /// `Scope::threads` of the scope.
+    threads: &'scope spin::Mutex<Vec<(Tid, fn(usize))>>,
+    mark: PhantomData<&'scope T>,
+}
+
+impl<'scope, T> ScopedJoinHandle<'scope, T> {
+    /// Extracts a handle to the underlying thread.
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    /// Waits for the associated thread to finish.
+    ///
+    /// Return `Err` if the thread panicked or was cancelled.
+    pub fn join(self) -> Result<T, JoinError> {
+        let tid = self.thread.tid;
+        // take it over from the scope
+        no_interrupt(|| self.threads.lock().retain(|&(t, _)| t != tid));
+        join_thread(tid).map(|exit_code| unsafe { *Box::from_raw(exit_code as *mut T) })
+    }
+
+    /// Requests the thread to be cancelled. See `JoinHandle::cancel`.
+    pub fn cancel(&self) -> bool {
+        no_interrupt(|| processor().manager().kill(self.thread.tid))
+    }
+}
+
+/// Declare a new thread local storage key of type `std_thread::LocalKey`.
+///
+/// Each thread gets its own copy of the value, initialized by the given expression
+/// on first access, and dropped when the thread exits.
+///
+/// ```ignore
+/// thread_local!(static COUNTER: RefCell<u32> = RefCell::new(0));
+/// ```
+#[macro_export]
+macro_rules! thread_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => (
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init);
+        $crate::thread_local!($($rest)*);
+    );
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => (
+        $(#[$attr])* $vis static $name: $crate::std_thread::LocalKey<$t> =
+            $crate::std_thread::LocalKey {
+                __init: {
+                    fn __init() -> $t {
+                        $init
+                    }
+                    __init
+                },
+            };
+    );
+}
+
+/// A thread local storage key which owns its contents.
+///
+/// Created by the `thread_local!` macro. The values live in the `ThreadPool`
+/// together with the thread, and their destructors run when the thread exits.
+/// Destructors don't run on the exiting thread, so they can't use `current()`.
+pub struct LocalKey<T: 'static> {
+    #[doc(hidden)]
+    pub __init: fn() -> T,
+}
+
+/// An error returned by `LocalKey::try_with`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct AccessError;
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("thread local is not accessible here")
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Acquires a reference to the value in this TLS key.
+    ///
+    /// Panics if not called by a thread, or the thread is exiting.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.try_with(f)
+            .expect("cannot access a TLS value outside a thread or during destruction")
+    }
+
+    /// Acquires a reference to the value in this TLS key,
+    /// or return an error if not called by a thread, or the thread is exiting.
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let key = self as *const Self as usize;
+        let tid = processor().tid_option().ok_or(AccessError)?;
+        let manager = processor().manager();
+        let value = match no_interrupt(|| manager.local(tid, key)) {
+            Some(value) => value,
+            None => {
+                // initialize without holding the thread
+                let value: Box<dyn Any> = Box::new((self.__init)());
+                no_interrupt(|| manager.init_local(tid, key, value)).ok_or(AccessError)?
+            }
+        };
+        // The value lives until the thread exits, and only this thread can reach it.
+        let value = unsafe { &*value };
+        Ok(f(value.downcast_ref::<T>().expect("TLS value type mismatch")))
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Do what `terminate_current_on_panic` does on a panic,
+    /// as a real one can't unwind out of a thread spawned on a `TestCpu`.
+    fn fake_panic(message: &str) -> ! {
+        let tid = current().id();
+        assert!(no_interrupt(|| processor()
+            .manager()
+            .exit_on_panic(tid, message.to_string())));
+        yield_now();
+        unreachable!()
+    }
+
+    #[test]
+    fn scope_joins_children() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let tid = cpu.spawn(|| {
+            let done = AtomicUsize::new(0);
+            scope(|s| {
+                for i in 0..3 {
+                    let done = &done;
+                    s.spawn(move || {
+                        // still running after the scope's closure returns
+                        for _ in 0..=i {
+                            yield_now();
+                        }
+                        done.fetch_add(1, Ordering::SeqCst);
+                    });
+                }
+                assert_eq!(done.load(Ordering::SeqCst), 0);
+            });
+            assert_eq!(done.load(Ordering::SeqCst), 3);
+        });
+        cpu.join(tid);
+    }
+
+    #[test]
+    fn scope_panics_on_child_panic() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let tid = cpu.spawn(|| {
+            let result = std::panic::catch_unwind(|| {
+                scope(|s| {
+                    s.spawn(|| fake_panic("child"));
+                    s.spawn(|| 1);
+                })
+            });
+            let message = result.unwrap_err();
+            assert_eq!(
+                message.downcast_ref::<&str>(),
+                Some(&"a scoped thread panicked")
+            );
+            // but not if it's joined by its handle
+            let result = scope(|s| s.spawn(|| fake_panic("child")).join());
+            match result {
+                Err(JoinError::Panicked(message)) => assert_eq!(message, "child"),
+                _ => panic!("expected a panic"),
+            }
+        });
+        cpu.join(tid);
+    }
+
+    #[test]
+    fn unpark_before_park() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let parker = cpu.spawn(|| {
+            // the token is kept for the next park, which doesn't block
+            current().unpark();
+            park();
+            current().unpark();
+            assert!(!park_timeout(Duration::from_secs(10)));
+            // only one token, this one blocks until unparked
+            current().unpark();
+            current().unpark();
+            park();
+            park();
+        });
+        let unparker = cpu.spawn(move || {
+            wait_sleeping(parker);
+            Thread { tid: parker }.unpark();
+        });
+        cpu.join(unparker);
+        cpu.join(parker);
     }
 }
@@ -0,0 +1,163 @@
+//! A barrier putting waiters to sleep
+
+use super::*;
+use core::cell::Cell;
+
+/// A barrier enables multiple threads to synchronize the beginning of some computation.
+pub struct Barrier {
+    state: spin::Mutex<BarrierState>,
+    waiters: WaitQueue,
+    num_threads: usize,
+}
+
+struct BarrierState {
+    /// Number of threads arrived in this round.
+    count: usize,
+    /// Bumped when a round completes.
+    generation: usize,
+}
+
+/// Returned by `Barrier::wait` when all threads in the `Barrier` have rendezvoused.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Returns whether this thread is the "leader thread" of the round,
+    /// i.e. the last one arrived. Exactly one thread in each round is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl Barrier {
+    /// Creates a new barrier that can block a given number of threads.
+    ///
+    /// A barrier of 0 or 1 thread never blocks.
+    pub fn new(n: usize) -> Self {
+        Barrier {
+            state: spin::Mutex::new(BarrierState {
+                count: 0,
+                generation: 0,
+            }),
+            waiters: WaitQueue::new(),
+            num_threads: n,
+        }
+    }
+
+    /// Blocks the current thread until all threads have rendezvoused here.
+    ///
+    /// The barrier is reusable after all threads have rendezvoused.
+    pub fn wait(&self) -> BarrierWaitResult {
+        // the round it arrived in
+        let generation = Cell::new(None);
+        wait_until_or_cancel(
+            &self.state,
+            &self.waiters,
+            |state| match generation.get() {
+                // arrive
+                None => {
+                    state.count += 1;
+                    if state.count < self.num_threads {
+                        generation.set(Some(state.generation));
+                        return None;
+                    }
+                    state.count = 0;
+                    state.generation = state.generation.wrapping_add(1);
+                    notify_all(&self.waiters);
+                    Some(BarrierWaitResult(true))
+                }
+                // woken up, check whether the round is over
+                Some(generation) if generation == state.generation => None,
+                Some(_) => Some(BarrierWaitResult(false)),
+            },
+            // killed before the round is over, take back its arrival
+            |state| {
+                if generation.get() == Some(state.generation) {
+                    state.count -= 1;
+                }
+            },
+        )
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn single_thread_is_leader() {
+        let barrier = Barrier::new(1);
+        assert!(barrier.wait().is_leader());
+        assert!(barrier.wait().is_leader());
+    }
+
+    #[test]
+    fn empty_barrier_never_blocks() {
+        let barrier = Barrier::new(0);
+        assert!(barrier.wait().is_leader());
+    }
+
+    #[test]
+    fn sleep_until_all_arrive() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let barrier = Arc::new(Barrier::new(3));
+        let leaders = Arc::new(AtomicUsize::new(0));
+        let spawn_waiter = || {
+            let (barrier, leaders) = (barrier.clone(), leaders.clone());
+            cpu.spawn(move || {
+                if barrier.wait().is_leader() {
+                    leaders.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+        };
+        let waiters = [spawn_waiter(), spawn_waiter()];
+        let last = cpu.spawn({
+            move || {
+                for &tid in waiters.iter() {
+                    wait_sleeping(tid);
+                }
+                // the last one arrived is the leader
+                assert!(barrier.wait().is_leader());
+            }
+        });
+        cpu.join(last);
+        for &tid in waiters.iter() {
+            cpu.join(tid);
+        }
+        assert_eq!(leaders.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn killed_waiter_leaves_round() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let barrier = Arc::new(Barrier::new(2));
+        let killed = cpu.spawn({
+            let barrier = barrier.clone();
+            move || {
+                barrier.wait();
+                panic!("killed waiter passed the barrier");
+            }
+        });
+        let killer = cpu.spawn(move || {
+            wait_sleeping(killed);
+            assert!(crate::std_thread::processor().manager().kill(killed));
+        });
+        cpu.join(killer);
+        cpu.join(killed);
+        // the round still takes 2 threads
+        let waiter = cpu.spawn({
+            let barrier = barrier.clone();
+            move || assert!(!barrier.wait().is_leader())
+        });
+        let last = cpu.spawn(move || {
+            wait_sleeping(waiter);
+            assert!(barrier.wait().is_leader());
+        });
+        cpu.join(last);
+        cpu.join(waiter);
+    }
+}
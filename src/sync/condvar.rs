@@ -0,0 +1,164 @@
+//! A condition variable putting waiters to sleep
+
+use super::*;
+use crate::std_thread::dur_to_ticks;
+use core::time::Duration;
+
+/// A Condition Variable
+///
+/// Used with `sync::Mutex` to block a thread while waiting for an event.
+#[derive(Default)]
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+/// Whether a timed wait on a condition variable returned due to a time out or not.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait was known to have timed out.
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    /// Creates a new condition variable.
+    pub fn new() -> Self {
+        Condvar::default()
+    }
+
+    /// Blocks the current thread until this condition variable receives a notification.
+    ///
+    /// The mutex of `guard` is released while waiting, and re-acquired before return.
+    /// Spurious wakeups are possible, so check the condition in a loop, or use `wait_while`.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let (pool, tid) = current();
+        let mutex = guard.mutex;
+        self.sleep(pool, tid, guard, 0);
+        mutex.lock()
+    }
+
+    /// Blocks the current thread until `condition` returns `false`.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Waits on this condition variable for a notification, timing out after `dur`.
+    ///
+    /// The timeout is driven by the timer of the `ThreadPool`,
+    /// so it's measured in ticks and rounded up to at least one tick.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        dur: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let (pool, tid) = current();
+        let mutex = guard.mutex;
+        let notified = self.sleep(pool, tid, guard, dur_to_ticks(dur).max(1));
+        // If not notified, it's the timer.
+        (mutex.lock(), WaitTimeoutResult(!notified))
+    }
+
+    /// Release `guard` and sleep for `ticks` ticks, 0 for ever, until notified.
+    /// Return whether it was notified.
+    ///
+    /// It's a cancellation point, where a killed thread exits without the mutex.
+    fn sleep<T: ?Sized>(
+        &self,
+        pool: &ThreadPool,
+        tid: Tid,
+        guard: MutexGuard<'_, T>,
+        ticks: usize,
+    ) -> bool {
+        let cancelled = no_interrupt(|| {
+            // enqueue before unlocking, so a notification after unlock can't be missed
+            pool.sleep(tid, ticks);
+            self.waiters.push(tid);
+            drop(guard);
+            // the cancellation was deferred by the mutex until now
+            pool.cancel_pending(tid) && self.waiters.remove(tid)
+        });
+        if cancelled {
+            exit_cancelled();
+        }
+        trace!("condvar: thread {} sleep", tid);
+        block();
+        match no_interrupt(|| leave_queue(&self.waiters, pool, tid)) {
+            Some(notified) => notified,
+            None => exit_cancelled(),
+        }
+    }
+
+    /// Wakes up one blocked thread on this condvar.
+    pub fn notify_one(&self) {
+        no_interrupt(|| {
+            self.waiters.notify_one(processor().manager());
+        });
+    }
+
+    /// Wakes up all blocked threads on this condvar.
+    pub fn notify_all(&self) {
+        no_interrupt(|| {
+            self.waiters.notify_all(processor().manager());
+        });
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::std_thread;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn wait_timeout() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let pair = Arc::new((Mutex::new(false), Condvar::new()));
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let waiter = cpu.spawn({
+            let pair = pair.clone();
+            let timed_out = timed_out.clone();
+            move || {
+                let (mutex, condvar) = &*pair;
+                let guard = mutex.lock();
+                let (guard, result) = condvar.wait_timeout(guard, Duration::from_millis(20));
+                assert!(result.timed_out());
+                assert!(!*guard);
+                timed_out.store(true, Ordering::SeqCst);
+                let (guard, result) = condvar.wait_timeout(guard, Duration::from_secs(10));
+                assert!(!result.timed_out());
+                assert!(*guard);
+            }
+        });
+        let driver = cpu.spawn(move || {
+            let pool = std_thread::processor().manager();
+            wait_sleeping(waiter);
+            // play the timer interrupt, until the wait times out
+            while !timed_out.load(Ordering::SeqCst) {
+                no_interrupt(|| pool.tick(0, None));
+                std_thread::yield_now();
+            }
+            wait_sleeping(waiter);
+            let (mutex, condvar) = &*pair;
+            *mutex.lock() = true;
+            condvar.notify_one();
+        });
+        cpu.join(driver);
+        cpu.join(waiter);
+    }
+}
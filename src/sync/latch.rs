@@ -0,0 +1,104 @@
+//! A countdown latch putting waiters to sleep
+
+use super::*;
+
+/// A countdown latch
+///
+/// Initialized with a count, which is decreased by `count_down`.
+/// Threads calling `wait` sleep until the count reaches zero,
+/// e.g. to wait for N spawned workers to finish their setup.
+/// Unlike `Barrier`, it's one-shot and the counting threads never block.
+pub struct Latch {
+    count: spin::Mutex<usize>,
+    waiters: WaitQueue,
+}
+
+impl Latch {
+    /// Creates a new latch with the initial count specified.
+    pub fn new(count: usize) -> Self {
+        Latch {
+            count: spin::Mutex::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Decrease the count by one, waking up all waiters if it reaches zero.
+    ///
+    /// Do nothing if the count is already zero.
+    pub fn count_down(&self) {
+        no_interrupt(|| {
+            let mut count = self.count.lock();
+            if *count == 0 {
+                return;
+            }
+            *count -= 1;
+            if *count == 0 {
+                notify_all(&self.waiters);
+            }
+        });
+    }
+
+    /// Blocks the current thread until the count reaches zero.
+    pub fn wait(&self) {
+        wait_until(&self.count, &self.waiters, |count| match *count {
+            0 => Some(()),
+            _ => None,
+        });
+    }
+
+    /// Return whether the count has reached zero, without blocking.
+    pub fn try_wait(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// The current count.
+    pub fn count(&self) -> usize {
+        no_interrupt(|| *self.count.lock())
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use crate::thread_pool::Status;
+    use crate::std_thread;
+
+    #[test]
+    fn count_down_to_zero() {
+        let latch = Latch::new(2);
+        assert!(!latch.try_wait());
+        latch.count_down();
+        assert_eq!(latch.count(), 1);
+        latch.count_down();
+        assert!(latch.try_wait());
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        latch.wait();
+    }
+
+    #[test]
+    fn wait_sleeps_until_zero() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let latch = Arc::new(Latch::new(2));
+        let waiter = cpu.spawn({
+            let latch = latch.clone();
+            move || {
+                latch.wait();
+                assert_eq!(latch.count(), 0);
+            }
+        });
+        let counter = cpu.spawn(move || {
+            wait_sleeping(waiter);
+            latch.count_down();
+            std_thread::yield_now();
+            let pool = std_thread::processor().manager();
+            assert_eq!(pool.status(waiter), Some(Status::Sleeping));
+            latch.count_down();
+        });
+        cpu.join(counter);
+        cpu.join(waiter);
+    }
+}
@@ -0,0 +1,213 @@
+//! `std::sync`-like synchronization primitives
+//!
+//! Based on `std_thread`. Waiting threads sleep in a `WaitQueue`
+//! and are woken up by `ThreadPool::wakeup`, instead of spinning.
+//!
+//! These primitives must be used by threads running on a `Processor`,
+//! not in interrupt handlers. The non-blocking `try_*` methods are fine anywhere.
+
+pub use self::barrier::{Barrier, BarrierWaitResult};
+pub use self::condvar::{Condvar, WaitTimeoutResult};
+pub use self::latch::Latch;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::once::Once;
+pub use self::pi_mutex::{PiMutex, PiMutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::{Semaphore, SemaphoreGuard};
+
+pub mod mpsc;
+
+mod barrier;
+mod condvar;
+mod latch;
+mod mutex;
+mod once;
+mod pi_mutex;
+mod rwlock;
+mod semaphore;
+
+use crate::interrupt::no_interrupt;
+use crate::std_thread::{processor, yield_now};
+use crate::thread_pool::{ThreadPool, Tid};
+use crate::wait_queue::WaitQueue;
+use log::*;
+
+/// Get the `ThreadPool` and the tid of the current thread.
+fn current() -> (&'static ThreadPool, Tid) {
+    let processor = processor();
+    (processor.manager(), processor.tid())
+}
+
+/// What an attempt to take something ended with.
+enum Wait<T> {
+    /// Got it.
+    Done(T),
+    /// Going to sleep.
+    Block,
+    /// The thread is killed instead of going to sleep, see `exit_cancelled`.
+    Cancelled,
+}
+
+/// Switch out the current thread, which has put itself to sleep.
+///
+/// Unlike `yield_now`, it's not a cancellation point: a killed thread comes back,
+/// so it can undo its bookkeeping (e.g. pass on a notification it took)
+/// before `exit_cancelled`.
+fn block() {
+    no_interrupt(|| processor().switch_out());
+}
+
+/// Exit the current thread, which is killed and not deferring the cancellation.
+fn exit_cancelled() -> ! {
+    yield_now();
+    unreachable!()
+}
+
+/// Called by thread `tid` woken up after waiting in `queue`.
+///
+/// Remove it from `queue` if it's still there, i.e. woken up by others than a notifier,
+/// and return whether it was notified. If it's going to exit, pass the notification
+/// to the next waiter, so it's not lost, and return `None`.
+fn leave_queue(queue: &WaitQueue, pool: &ThreadPool, tid: Tid) -> Option<bool> {
+    let notified = !queue.remove(tid);
+    if pool.cancel_pending(tid) {
+        if notified {
+            queue.notify_one(pool);
+        }
+        return None;
+    }
+    Some(notified)
+}
+
+/// Block the current thread on `queue` until `f` returns `Some`.
+///
+/// `f` is called with `state` locked and interrupt disabled.
+/// If it returns `None`, the thread joins `queue` before `state` is unlocked,
+/// so a notification sent after that can't be missed.
+///
+/// It's a cancellation point when the thread has to wait.
+/// A killed waiter passes on the notification it took, if any, before it exits.
+///
+/// The current thread is only touched if it has to wait.
+fn wait_until<S, T>(
+    state: &spin::Mutex<S>,
+    queue: &WaitQueue,
+    f: impl FnMut(&mut S) -> Option<T>,
+) -> T {
+    wait_until_or_cancel(state, queue, f, |_| {})
+}
+
+/// Like `wait_until`, but call `cancel` with `state` locked if the thread is going to exit,
+/// e.g. to undo what `f` did to `state` before returning `None`.
+fn wait_until_or_cancel<S, T>(
+    state: &spin::Mutex<S>,
+    queue: &WaitQueue,
+    mut f: impl FnMut(&mut S) -> Option<T>,
+    mut cancel: impl FnMut(&mut S),
+) -> T {
+    let mut waited = None;
+    loop {
+        let ret = no_interrupt(|| {
+            let mut state = state.lock();
+            if let Some((pool, tid)) = waited {
+                if leave_queue(queue, pool, tid).is_none() {
+                    cancel(&mut state);
+                    return Wait::Cancelled;
+                }
+            }
+            if let Some(ret) = f(&mut state) {
+                return Wait::Done(ret);
+            }
+            let (pool, tid) = current();
+            if pool.cancel_pending(tid) {
+                cancel(&mut state);
+                return Wait::Cancelled;
+            }
+            queue.wait(pool, tid);
+            waited = Some((pool, tid));
+            Wait::Block
+        });
+        match ret {
+            Wait::Done(ret) => return ret,
+            Wait::Block => block(),
+            Wait::Cancelled => exit_cancelled(),
+        }
+    }
+}
+
+/// Like `wait_until`, but give up after `ticks` timer ticks and return `None`.
+///
+/// The thread waits only once: if it's woken up but `f` still returns `None`,
+/// it's considered timed out.
+fn wait_timeout_until<S, T>(
+    state: &spin::Mutex<S>,
+    queue: &WaitQueue,
+    ticks: usize,
+    mut f: impl FnMut(&mut S) -> Option<T>,
+) -> Option<T> {
+    let mut waited = None;
+    loop {
+        let ret = no_interrupt(|| {
+            let mut state = state.lock();
+            if let Some((pool, tid)) = waited {
+                // not removed by a notifier if timed out
+                if leave_queue(queue, pool, tid).is_none() {
+                    return Wait::Cancelled;
+                }
+                return Wait::Done(f(&mut state));
+            }
+            if let Some(ret) = f(&mut state) {
+                return Wait::Done(Some(ret));
+            }
+            let (pool, tid) = current();
+            if pool.cancel_pending(tid) {
+                return Wait::Cancelled;
+            }
+            pool.sleep(tid, ticks.max(1));
+            queue.push(tid);
+            waited = Some((pool, tid));
+            Wait::Block
+        });
+        match ret {
+            Wait::Done(ret) => return ret,
+            Wait::Block => block(),
+            Wait::Cancelled => exit_cancelled(),
+        }
+    }
+}
+
+/// Called when the current thread takes a lock, in the same critical section,
+/// so it never holds the lock without its cancellation deferred.
+/// Defer its cancellation until `leave_lock`, see `ThreadPool::kill`.
+fn enter_lock() {
+    no_interrupt(|| {
+        let processor = processor();
+        if let Some(tid) = processor.tid_option() {
+            processor.manager().disable_cancel(tid);
+        }
+    });
+}
+
+/// Called when the current thread releases a lock.
+fn leave_lock() {
+    no_interrupt(|| {
+        let processor = processor();
+        if let Some(tid) = processor.tid_option() {
+            processor.manager().enable_cancel(tid);
+        }
+    });
+}
+
+/// Wake up one thread in `queue`, if any.
+fn notify_one(queue: &WaitQueue) {
+    if !queue.is_empty() {
+        queue.notify_one(processor().manager());
+    }
+}
+
+/// Wake up all threads in `queue`, if any.
+fn notify_all(queue: &WaitQueue) {
+    if !queue.is_empty() {
+        queue.notify_all(processor().manager());
+    }
+}
@@ -0,0 +1,458 @@
+//! Multi-producer, single-consumer FIFO queue communication primitives
+//!
+//! Like `std::sync::mpsc`, but blocked senders and receivers sleep in the `ThreadPool`.
+//!
+//! - `channel` creates an unbounded channel, on which `send` never blocks.
+//! - `sync_channel` creates a bounded channel, on which `send` blocks while it's full.
+//!   A bound of 0 makes a rendezvous channel: `send` blocks until the message is received.
+
+use super::*;
+use crate::std_thread::{dur_to_ticks, Instant};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cell::Cell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::time::Duration;
+
+/// The sending-half of an unbounded channel.
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// The sending-half of a bounded channel.
+pub struct SyncSender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// The receiving half of a channel.
+///
+/// Not `Sync`: there is a single consumer.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+    marker: PhantomData<Cell<()>>,
+}
+
+/// An error returned from `send`: the receiver has been dropped.
+/// It contains the message which could not be sent.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct SendError<T>(pub T);
+
+/// An error returned from `try_send`.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiver has been dropped.
+    Disconnected(T),
+}
+
+/// An error returned from `recv`: all senders have been dropped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RecvError;
+
+/// An error returned from `try_recv`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TryRecvError {
+    /// The channel is empty now.
+    Empty,
+    /// The channel is empty and all senders have been dropped.
+    Disconnected,
+}
+
+/// An error returned from `recv_timeout`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RecvTimeoutError {
+    /// No message arrived in time.
+    Timeout,
+    /// The channel is empty and all senders have been dropped.
+    Disconnected,
+}
+
+struct Chan<T> {
+    state: spin::Mutex<State<T>>,
+    /// The receiver waiting for messages.
+    receivers: WaitQueue,
+    /// Senders waiting for space, or for their message to be received.
+    senders: WaitQueue,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    /// Max number of queued messages. `None` for unbounded.
+    bound: Option<usize>,
+    /// Number of alive senders.
+    senders: usize,
+    receiver_alive: bool,
+    /// Number of messages ever sent and received. Used by rendezvous channels.
+    sent: usize,
+    received: usize,
+}
+
+/// Creates a new asynchronous channel, returning the sender/receiver halves.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let chan = Chan::new(None);
+    (Sender { chan: chan.clone() }, Receiver::new(chan))
+}
+
+/// Creates a new synchronous, bounded channel.
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+    let chan = Chan::new(Some(bound));
+    (SyncSender { chan: chan.clone() }, Receiver::new(chan))
+}
+
+impl<T> Chan<T> {
+    fn new(bound: Option<usize>) -> Arc<Self> {
+        Arc::new(Chan {
+            state: spin::Mutex::new(State {
+                queue: VecDeque::new(),
+                bound,
+                senders: 1,
+                receiver_alive: true,
+                sent: 0,
+                received: 0,
+            }),
+            receivers: WaitQueue::new(),
+            senders: WaitQueue::new(),
+        })
+    }
+
+    /// Push a message if there is space.
+    /// Return the sequence number of the message.
+    fn try_push(&self, state: &mut State<T>, t: &mut Option<T>) -> Result<usize, TrySendError<T>> {
+        if !state.receiver_alive {
+            return Err(TrySendError::Disconnected(t.take().unwrap()));
+        }
+        if let Some(bound) = state.bound {
+            // a rendezvous channel holds one message in transfer
+            if state.queue.len() >= bound.max(1) {
+                return Err(TrySendError::Full(t.take().unwrap()));
+            }
+        }
+        state.queue.push_back(t.take().unwrap());
+        state.sent += 1;
+        notify_one(&self.receivers);
+        Ok(state.sent)
+    }
+
+    fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let mut t = Some(t);
+        let seq = wait_until(&self.state, &self.senders, |state| {
+            match self.try_push(state, &mut t) {
+                Ok(seq) => Some(Ok(seq)),
+                Err(TrySendError::Disconnected(t)) => Some(Err(SendError(t))),
+                Err(TrySendError::Full(x)) => {
+                    t = Some(x);
+                    None
+                }
+            }
+        })?;
+        let rendezvous = no_interrupt(|| self.state.lock().bound == Some(0));
+        if rendezvous {
+            // wait until the message is received, or take it back if the receiver is gone
+            return wait_until(&self.state, &self.senders, |state| {
+                if state.received >= seq {
+                    Some(Ok(()))
+                } else if !state.receiver_alive {
+                    // messages after the received ones are queued in order
+                    let t = state.queue.remove(seq - state.received - 1).unwrap();
+                    Some(Err(SendError(t)))
+                } else {
+                    None
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        let mut t = Some(t);
+        no_interrupt(|| {
+            let mut state = self.state.lock();
+            self.try_push(&mut state, &mut t).map(|_| ())
+        })
+    }
+
+    fn try_pop(&self, state: &mut State<T>) -> Result<T, TryRecvError> {
+        match state.queue.pop_front() {
+            Some(t) => {
+                state.received += 1;
+                // wake up senders waiting for space or for a rendezvous
+                if state.bound.is_some() {
+                    notify_all(&self.senders);
+                }
+                Ok(t)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    fn recv(&self) -> Result<T, RecvError> {
+        wait_until(&self.state, &self.receivers, |state| {
+            match self.try_pop(state) {
+                Ok(t) => Some(Ok(t)),
+                Err(TryRecvError::Disconnected) => Some(Err(RecvError)),
+                Err(TryRecvError::Empty) => None,
+            }
+        })
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        no_interrupt(|| {
+            let mut state = self.state.lock();
+            self.try_pop(&mut state)
+        })
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            // never reached
+            None => return self.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        // may be woken up early by others, wait again
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return self.try_recv().map_err(|e| match e {
+                    TryRecvError::Empty => RecvTimeoutError::Timeout,
+                    TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                });
+            }
+            let ticks = dur_to_ticks(deadline - now);
+            let ret = wait_timeout_until(&self.state, &self.receivers, ticks, |state| {
+                match self.try_pop(state) {
+                    Ok(t) => Some(Ok(t)),
+                    Err(TryRecvError::Disconnected) => Some(Err(RecvTimeoutError::Disconnected)),
+                    Err(TryRecvError::Empty) => None,
+                }
+            });
+            if let Some(ret) = ret {
+                return ret;
+            }
+        }
+    }
+
+    fn add_sender(&self) {
+        no_interrupt(|| self.state.lock().senders += 1);
+    }
+
+    fn drop_sender(&self) {
+        no_interrupt(|| {
+            let mut state = self.state.lock();
+            state.senders -= 1;
+            if state.senders == 0 {
+                notify_all(&self.receivers);
+            }
+        });
+    }
+
+    fn drop_receiver(&self) {
+        no_interrupt(|| {
+            let mut state = self.state.lock();
+            state.receiver_alive = false;
+            notify_all(&self.senders);
+        });
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send a value on this channel. Never blocks.
+    ///
+    /// Return an error with the value if the receiver has been dropped.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.chan.send(t)
+    }
+}
+
+impl<T> SyncSender<T> {
+    /// Send a value on this channel, blocking while the channel is full.
+    /// On a rendezvous channel, also block until the value is received.
+    ///
+    /// Return an error with the value if the receiver has been dropped
+    /// before receiving it.
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.chan.send(t)
+    }
+
+    /// Attempts to send a value on this channel without blocking.
+    ///
+    /// On a rendezvous channel, succeeds only if no other message is in transfer,
+    /// and doesn't wait for the message to be received.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        self.chan.try_send(t)
+    }
+}
+
+impl<T> Receiver<T> {
+    fn new(chan: Arc<Chan<T>>) -> Self {
+        Receiver {
+            chan,
+            marker: PhantomData,
+        }
+    }
+
+    /// Wait for a value on this channel.
+    ///
+    /// Return an error if the channel is empty and all senders have been dropped.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.chan.recv()
+    }
+
+    /// Attempts to return a pending value on this channel without blocking.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.chan.try_recv()
+    }
+
+    /// Wait for a value on this channel, at most for `timeout`.
+    ///
+    /// The timeout is driven by the timer of the `ThreadPool`,
+    /// so it's rounded up to a timer tick.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.chan.recv_timeout(timeout)
+    }
+
+    /// Returns an iterator that will block waiting for messages,
+    /// until all senders have been dropped.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { rx: self }
+    }
+}
+
+/// An iterator over messages on a `Receiver`. See `Receiver::iter`.
+pub struct Iter<'a, T: 'a> {
+    rx: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        self.chan.add_sender();
+        SyncSender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> Drop for SyncSender<T> {
+    fn drop(&mut self) {
+        self.chan.drop_sender();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.drop_receiver();
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError { .. }")
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::std_thread;
+    use crate::test_util::{wait_sleeping, TestCpu};
+
+    #[test]
+    fn unbounded_try_recv() {
+        let (tx, rx) = channel();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        tx.send(1).unwrap();
+        tx.clone().send(2).unwrap();
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(rx.recv(), Ok(2));
+        drop(tx);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(rx.recv(), Err(RecvError));
+    }
+
+    #[test]
+    fn bounded_full() {
+        let (tx, rx) = sync_channel(1);
+        tx.try_send(1).unwrap();
+        match tx.try_send(2) {
+            Err(TrySendError::Full(2)) => {}
+            _ => panic!("channel should be full"),
+        }
+        assert_eq!(rx.recv(), Ok(1));
+        tx.send(3).unwrap();
+        assert_eq!(rx.iter().next(), Some(3));
+    }
+
+    #[test]
+    fn receiver_dropped() {
+        let (tx, rx) = channel();
+        drop(rx);
+        assert_eq!(tx.send(1).map_err(|e| e.0), Err(1));
+    }
+
+    #[test]
+    fn rendezvous_receiver_dropped() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let (tx, rx) = sync_channel(0);
+        let sender = cpu.spawn(move || {
+            assert_eq!(tx.send(1).map_err(|e| e.0), Err(1));
+        });
+        cpu.spawn(move || {
+            // the message is queued, but never received
+            wait_sleeping(sender);
+            drop(rx);
+        });
+        cpu.join(sender);
+    }
+
+    #[test]
+    fn recv_timeout_spurious_wakeup() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let (tx, rx) = channel();
+        let receiver = cpu.spawn(move || {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok(1));
+        });
+        cpu.spawn(move || {
+            wait_sleeping(receiver);
+            std_thread::processor().manager().wakeup(receiver);
+            // no timer tick passes, so it keeps waiting
+            std_thread::yield_now();
+            wait_sleeping(receiver);
+            tx.send(1).unwrap();
+        });
+        cpu.join(receiver);
+    }
+}
@@ -0,0 +1,152 @@
+//! A mutual exclusion lock putting waiters to sleep
+
+use super::*;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+/// A mutual exclusion primitive useful for protecting shared data
+///
+/// Unlike `spin::Mutex`, a thread failed to take the lock goes to sleep
+/// until the lock is released.
+pub struct Mutex<T: ?Sized> {
+    locked: spin::Mutex<bool>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// An RAII guard of `Mutex`. The lock is released when it's dropped.
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    pub(super) mutex: &'a Mutex<T>,
+    /// Not `Send`: the lock must be released by the thread holding it,
+    /// which defers its cancellation meanwhile.
+    marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    /// Creates a new mutex in an unlocked state.
+    pub fn new(data: T) -> Self {
+        Mutex {
+            locked: spin::Mutex::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquires the mutex, blocking the current thread until it is able to do so.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        wait_until(&self.locked, &self.waiters, |locked| {
+            if *locked {
+                return None;
+            }
+            *locked = true;
+            enter_lock();
+            Some(())
+        });
+        MutexGuard { mutex: self, marker: PhantomData }
+    }
+
+    /// Attempts to acquire this lock without blocking.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let acquired = no_interrupt(|| {
+            let mut locked = self.locked.lock();
+            if *locked {
+                return false;
+            }
+            *locked = true;
+            enter_lock();
+            true
+        });
+        if !acquired {
+            return None;
+        }
+        Some(MutexGuard { mutex: self, marker: PhantomData })
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn unlock(&self) {
+        no_interrupt(|| {
+            *self.locked.lock() = false;
+            notify_one(&self.waiters);
+        });
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+        leave_lock();
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::std_thread;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn waiter_sleeps_until_unlock() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let mutex = Arc::new(Mutex::new(0));
+        let waiter = Arc::new(AtomicUsize::new(usize::max_value()));
+        let holder = cpu.spawn({
+            let (mutex, waiter) = (mutex.clone(), waiter.clone());
+            move || {
+                let mut guard = mutex.lock();
+                while waiter.load(Ordering::SeqCst) == usize::max_value() {
+                    std_thread::yield_now();
+                }
+                wait_sleeping(waiter.load(Ordering::SeqCst));
+                *guard += 1;
+            }
+        });
+        let tid = cpu.spawn(move || {
+            let mut guard = mutex.lock();
+            // only after the holder is done
+            assert_eq!(*guard, 1);
+            *guard += 1;
+        });
+        waiter.store(tid, Ordering::SeqCst);
+        cpu.join(holder);
+        cpu.join(tid);
+    }
+}
@@ -0,0 +1,236 @@
+//! A one-time initialization putting waiters to sleep
+
+use super::*;
+
+/// A synchronization primitive which can be used to run a one-time global initialization.
+///
+/// Unlike `spin::Once`, the initialization routine may block,
+/// and other threads calling `call_once` meanwhile sleep until it completes.
+pub struct Once {
+    state: spin::Mutex<OnceState>,
+    waiters: WaitQueue,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum OnceState {
+    Incomplete,
+    Running,
+    Complete,
+}
+
+impl Once {
+    /// Creates a new `Once` value.
+    pub fn new() -> Self {
+        Once {
+            state: spin::Mutex::new(OnceState::Incomplete),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Performs an initialization routine once and only once.
+    ///
+    /// If another thread is running the routine, block until it completes.
+    /// When this function returns, the routine has completed.
+    ///
+    /// The routine is never cancelled halfway, as the waiters would sleep forever:
+    /// a kill of the thread takes effect after it returns.
+    /// For the same reason a panic in it is not handled by `terminate_current_on_panic`,
+    /// like in a `std_thread::scope`. If the panic unwinds, a waiter runs its own routine instead.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        let run = wait_until(&self.state, &self.waiters, |state| match *state {
+            OnceState::Incomplete => {
+                *state = OnceState::Running;
+                Some(true)
+            }
+            OnceState::Running => None,
+            OnceState::Complete => Some(false),
+        });
+        if !run {
+            return;
+        }
+        let mut runner = Runner::new(self);
+        f();
+        runner.complete = true;
+    }
+
+    /// Returns `true` if `call_once` has completed.
+    pub fn is_completed(&self) -> bool {
+        no_interrupt(|| *self.state.lock() == OnceState::Complete)
+    }
+}
+
+/// Runs the routine of a `Once`, with the cancellation and the panic handling
+/// of the current thread deferred. Publishes the result when dropped.
+struct Runner<'a> {
+    once: &'a Once,
+    tid: Tid,
+    /// The routine has returned. Otherwise it's dropped on unwinding.
+    complete: bool,
+}
+
+impl<'a> Runner<'a> {
+    fn new(once: &'a Once) -> Self {
+        let (pool, tid) = current();
+        no_interrupt(|| {
+            pool.disable_cancel(tid);
+            pool.enter_scope(tid);
+        });
+        Runner {
+            once,
+            tid,
+            complete: false,
+        }
+    }
+}
+
+impl Drop for Runner<'_> {
+    fn drop(&mut self) {
+        no_interrupt(|| {
+            *self.once.state.lock() = if self.complete {
+                OnceState::Complete
+            } else {
+                OnceState::Incomplete
+            };
+            notify_all(&self.once.waiters);
+            let pool = processor().manager();
+            pool.leave_scope(self.tid);
+            pool.enable_cancel(self.tid);
+        });
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Once::new()
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::std_thread;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn run_only_once() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let tid = cpu.spawn(|| {
+            let once = Once::new();
+            let mut count = 0;
+            assert!(!once.is_completed());
+            once.call_once(|| count += 1);
+            once.call_once(|| count += 1);
+            assert!(once.is_completed());
+            assert_eq!(count, 1);
+        });
+        cpu.join(tid);
+    }
+
+    /// Spawn a thread calling `once.call_once`, which counts the routines run.
+    fn spawn_waiter(cpu: &TestCpu, once: &Arc<Once>, count: &Arc<AtomicUsize>) -> Tid {
+        let (once, count) = (once.clone(), count.clone());
+        cpu.spawn(move || {
+            once.call_once(|| {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+            assert!(once.is_completed());
+        })
+    }
+
+    #[test]
+    fn kill_deferred_while_running() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let once = Arc::new(Once::new());
+        let count = Arc::new(AtomicUsize::new(0));
+        let waiter = Arc::new(AtomicUsize::new(usize::max_value()));
+        let runner = cpu.spawn({
+            let (once, count, waiter) = (once.clone(), count.clone(), waiter.clone());
+            move || {
+                once.call_once(|| {
+                    while waiter.load(Ordering::SeqCst) == usize::max_value() {
+                        std_thread::yield_now();
+                    }
+                    wait_sleeping(waiter.load(Ordering::SeqCst));
+                    let pool = std_thread::processor().manager();
+                    assert!(pool.kill(std_thread::current().id()));
+                    // not a cancellation point in the routine
+                    std_thread::yield_now();
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+                std_thread::yield_now();
+                panic!("the kill never took effect");
+            }
+        });
+        let tid = spawn_waiter(&cpu, &once, &count);
+        waiter.store(tid, Ordering::SeqCst);
+        cpu.join(runner);
+        cpu.join(tid);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn panic_lets_waiter_run() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let once = Arc::new(Once::new());
+        let count = Arc::new(AtomicUsize::new(0));
+        let waiter = Arc::new(AtomicUsize::new(usize::max_value()));
+        let runner = cpu.spawn({
+            let (once, waiter) = (once.clone(), waiter.clone());
+            move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    once.call_once(|| {
+                        while waiter.load(Ordering::SeqCst) == usize::max_value() {
+                            std_thread::yield_now();
+                        }
+                        wait_sleeping(waiter.load(Ordering::SeqCst));
+                        panic!("routine failed");
+                    })
+                }));
+                assert!(result.is_err());
+            }
+        });
+        let tid = spawn_waiter(&cpu, &once, &count);
+        waiter.store(tid, Ordering::SeqCst);
+        cpu.join(runner);
+        cpu.join(tid);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn others_sleep_while_running() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let once = Arc::new(Once::new());
+        let count = Arc::new(AtomicUsize::new(0));
+        let other = Arc::new(AtomicUsize::new(usize::max_value()));
+        let runner = cpu.spawn({
+            let (once, count, other) = (once.clone(), count.clone(), other.clone());
+            move || {
+                once.call_once(|| {
+                    // block the other thread until the routine completes
+                    while other.load(Ordering::SeqCst) == usize::max_value() {
+                        std_thread::yield_now();
+                    }
+                    wait_sleeping(other.load(Ordering::SeqCst));
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        let tid = cpu.spawn({
+            let count = count.clone();
+            move || {
+                once.call_once(|| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                });
+                assert!(once.is_completed());
+            }
+        });
+        other.store(tid, Ordering::SeqCst);
+        cpu.join(runner);
+        cpu.join(tid);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,357 @@
+//! A mutual exclusion lock with priority inheritance
+
+use super::*;
+use crate::thread_pool::Status;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::cmp::Reverse;
+use core::ops::{Deref, DerefMut};
+
+/// A sleeping mutex with priority inheritance
+///
+/// While a thread is blocked on the lock, the owner runs with
+/// the higher one of their effective priorities (see `ThreadPool::get_pri`).
+/// If the owner is itself blocked on another `PiMutex`, the boost is passed on along the chain.
+///
+/// On unlock, the lock is handed over to the waiter with the highest priority
+/// (FIFO among equals), and the owner drops back to the priority it would have without this lock.
+pub struct PiMutex<T: ?Sized> {
+    state: spin::Mutex<State>,
+    data: UnsafeCell<T>,
+}
+
+#[derive(Default)]
+struct State {
+    owner: Option<Tid>,
+    waiters: Vec<Tid>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for PiMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for PiMutex<T> {}
+
+/// An RAII guard of `PiMutex`. The lock is released when it's dropped.
+pub struct PiMutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a PiMutex<T>,
+    /// Not `Send`, like `MutexGuard`.
+    marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for PiMutexGuard<'_, T> {}
+
+impl<T> PiMutex<T> {
+    /// Creates a new mutex in an unlocked state.
+    pub fn new(data: T) -> Self {
+        PiMutex {
+            state: spin::Mutex::new(State::default()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> PiMutex<T> {
+    /// Identify this lock in the `ThreadPool`.
+    fn id(&self) -> usize {
+        &self.state as *const _ as usize
+    }
+
+    /// Acquires the mutex, blocking the current thread until it is able to do so.
+    pub fn lock(&self) -> PiMutexGuard<'_, T> {
+        let (pool, tid) = current();
+        let mut waited = false;
+        loop {
+            let ret = no_interrupt(|| {
+                let mut state = self.state.lock();
+                match state.owner {
+                    None => {
+                        state.owner = Some(tid);
+                        enter_lock();
+                        Wait::Done(())
+                    }
+                    // handed over to us by `unlock`, which deferred our cancellation
+                    Some(owner) if owner == tid && waited => Wait::Done(()),
+                    Some(owner) => {
+                        assert_ne!(owner, tid, "PiMutex: deadlock on recursive locking");
+                        if pool.cancel_pending(tid) {
+                            state.waiters.retain(|&t| t != tid);
+                            return Wait::Cancelled;
+                        }
+                        if !waited {
+                            state.waiters.push(tid);
+                            pool.pi_block(tid, self.id(), owner);
+                        }
+                        // sleep again if woken up by others
+                        pool.sleep(tid, 0);
+                        Wait::Block
+                    }
+                }
+            });
+            match ret {
+                Wait::Done(()) => return PiMutexGuard { mutex: self, marker: PhantomData },
+                Wait::Block => {
+                    waited = true;
+                    trace!("pi mutex: thread {} sleep", tid);
+                    block();
+                }
+                Wait::Cancelled => exit_cancelled(),
+            }
+        }
+    }
+
+    /// Attempts to acquire this lock without blocking.
+    pub fn try_lock(&self) -> Option<PiMutexGuard<'_, T>> {
+        let (_, tid) = current();
+        let acquired = no_interrupt(|| {
+            let mut state = self.state.lock();
+            if state.owner.is_some() {
+                return false;
+            }
+            state.owner = Some(tid);
+            enter_lock();
+            true
+        });
+        if !acquired {
+            return None;
+        }
+        Some(PiMutexGuard { mutex: self, marker: PhantomData })
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn unlock(&self) {
+        no_interrupt(|| {
+            let pool = processor().manager();
+            let mut state = self.state.lock();
+            let owner = state.owner.take().expect("PiMutex: unlock without owner");
+            pool.pi_release(owner, self.id());
+            // forget exited and removed threads, and the killed ones going to exit
+            state.waiters.retain(|&tid| match pool.status(tid) {
+                Some(Status::Exited(_)) | None => false,
+                _ => !pool.cancel_pending(tid),
+            });
+            if state.waiters.is_empty() {
+                return;
+            }
+            // hand over to the first waiter of the highest priority
+            let next = (0..state.waiters.len())
+                .max_by_key(|&i| (pool.get_pri(state.waiters[i]), Reverse(i)))
+                .unwrap();
+            let next = state.waiters.remove(next);
+            state.owner = Some(next);
+            // take the lock on its behalf, so it's never killed owning the lock
+            pool.disable_cancel(next);
+            pool.pi_acquire(next);
+            // the rest waiters are blocked by the new owner now
+            for &tid in state.waiters.iter() {
+                pool.pi_block(tid, self.id(), next);
+            }
+            pool.wakeup(next);
+        });
+    }
+}
+
+impl<T: Default> Default for PiMutex<T> {
+    fn default() -> Self {
+        PiMutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for PiMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for PiMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for PiMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+        leave_lock();
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::{PTScheduler, RRScheduler};
+    use crate::std_thread;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn pool() -> &'static ThreadPool {
+        std_thread::processor().manager()
+    }
+
+    fn get_pri(tid: Tid) -> Option<u8> {
+        no_interrupt(|| pool().get_pri(tid))
+    }
+
+    /// Called by the thread holding a lock: raise the base priority of thread `tid`,
+    /// which then goes on from `wait_priority`, and wait for it to block.
+    ///
+    /// On a single CPU, a higher priority thread runs whenever it's ready,
+    /// so the waiters are let in one at a time.
+    fn raise_and_wait(tid: &AtomicUsize, priority: u8) {
+        while tid.load(Ordering::SeqCst) == usize::max_value() {
+            std_thread::yield_now();
+        }
+        let tid = tid.load(Ordering::SeqCst);
+        no_interrupt(|| pool().set_priority(tid, priority));
+        wait_sleeping(tid);
+    }
+
+    /// Wait for the holder to raise the priority of the current thread to `priority`.
+    fn wait_priority(priority: u8) {
+        let tid = std_thread::current().id();
+        while no_interrupt(|| pool().get_base_pri(tid)) != Some(priority) {
+            std_thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn boost_to_top_waiter() {
+        let cpu = TestCpu::new(PTScheduler::new(5));
+        let mutex = Arc::new(PiMutex::new(()));
+        let order = Arc::new(spin::Mutex::new(Vec::new()));
+        let middle = Arc::new(AtomicUsize::new(usize::max_value()));
+        let high = Arc::new(AtomicUsize::new(usize::max_value()));
+        let low = cpu.spawn({
+            let (mutex, middle, high) = (mutex.clone(), middle.clone(), high.clone());
+            move || {
+                let me = std_thread::current().id();
+                let guard = mutex.lock();
+                raise_and_wait(&middle, 2);
+                assert_eq!(get_pri(me), Some(2));
+                raise_and_wait(&high, 4);
+                assert_eq!(get_pri(me), Some(4));
+                drop(guard);
+                assert_eq!(get_pri(me), Some(1));
+            }
+        });
+        let spawn_waiter = |priority: u8| {
+            let (mutex, order) = (mutex.clone(), order.clone());
+            cpu.spawn(move || {
+                wait_priority(priority);
+                let _guard = mutex.lock();
+                order.lock().push(priority);
+            })
+        };
+        middle.store(spawn_waiter(2), Ordering::SeqCst);
+        high.store(spawn_waiter(4), Ordering::SeqCst);
+        for &tid in [low, middle.load(Ordering::SeqCst), high.load(Ordering::SeqCst)].iter() {
+            cpu.join(tid);
+        }
+        // handed over to the highest waiter first
+        assert_eq!(*order.lock(), [4, 2]);
+    }
+
+    #[test]
+    fn boost_along_chain() {
+        let cpu = TestCpu::new(PTScheduler::new(5));
+        let (first, second) = (Arc::new(PiMutex::new(())), Arc::new(PiMutex::new(())));
+        let middle = Arc::new(AtomicUsize::new(usize::max_value()));
+        let high = Arc::new(AtomicUsize::new(usize::max_value()));
+        // low holds `first`, wanted by middle holding `second`, wanted by high
+        let low = cpu.spawn({
+            let (first, middle, high) = (first.clone(), middle.clone(), high.clone());
+            move || {
+                let me = std_thread::current().id();
+                let guard = first.lock();
+                raise_and_wait(&middle, 2);
+                raise_and_wait(&high, 4);
+                assert_eq!(get_pri(me), Some(4));
+                assert_eq!(get_pri(middle.load(Ordering::SeqCst)), Some(4));
+                drop(guard);
+                assert_eq!(get_pri(me), Some(1));
+            }
+        });
+        middle.store(
+            cpu.spawn({
+                let (first, second) = (first.clone(), second.clone());
+                move || {
+                    let me = std_thread::current().id();
+                    wait_priority(2);
+                    let second = second.lock();
+                    let first = first.lock();
+                    // still boosted by high
+                    assert_eq!(get_pri(me), Some(4));
+                    drop(first);
+                    assert_eq!(get_pri(me), Some(4));
+                    drop(second);
+                    assert_eq!(get_pri(me), Some(2));
+                }
+            }),
+            Ordering::SeqCst,
+        );
+        high.store(
+            cpu.spawn(move || {
+                wait_priority(4);
+                let _second = second.lock();
+                assert_eq!(get_pri(std_thread::current().id()), Some(4));
+            }),
+            Ordering::SeqCst,
+        );
+        for &tid in [low, middle.load(Ordering::SeqCst), high.load(Ordering::SeqCst)].iter() {
+            cpu.join(tid);
+        }
+    }
+
+    #[test]
+    fn killed_waiter_skipped_on_unlock() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let mutex = Arc::new(PiMutex::new(0));
+        let killed = Arc::new(AtomicUsize::new(usize::max_value()));
+        let waiter = Arc::new(AtomicUsize::new(usize::max_value()));
+        let owner = cpu.spawn({
+            let (mutex, killed, waiter) = (mutex.clone(), killed.clone(), waiter.clone());
+            move || {
+                let mut guard = mutex.lock();
+                // wait for the others to block on the lock
+                while waiter.load(Ordering::SeqCst) == usize::max_value() {
+                    std_thread::yield_now();
+                }
+                let (killed, waiter) = (killed.load(Ordering::SeqCst), waiter.load(Ordering::SeqCst));
+                wait_sleeping(killed);
+                wait_sleeping(waiter);
+                // the first waiter would get the lock
+                assert!(std_thread::processor().manager().kill(killed));
+                *guard += 1;
+            }
+        });
+        let first = cpu.spawn({
+            let mutex = mutex.clone();
+            move || {
+                mutex.lock();
+                panic!("killed waiter took the lock");
+            }
+        });
+        killed.store(first, Ordering::SeqCst);
+        let second = cpu.spawn({
+            let mutex = mutex.clone();
+            move || {
+                let mut guard = mutex.lock();
+                *guard += 1;
+                assert_eq!(*guard, 2);
+            }
+        });
+        waiter.store(second, Ordering::SeqCst);
+        for &tid in [owner, first, second].iter() {
+            cpu.join(tid);
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! A reader-writer lock putting waiters to sleep
+
+use super::*;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+/// A reader-writer lock
+///
+/// Allows a number of readers or at most one writer at any point in time.
+/// Writers are preferred: once a writer is waiting, new readers are blocked,
+/// so a stream of readers can't starve writers.
+pub struct RwLock<T: ?Sized> {
+    state: spin::Mutex<State>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Number of readers holding the lock.
+    readers: usize,
+    /// Whether a writer holds the lock.
+    writer: bool,
+    /// Number of writers waiting for the lock.
+    waiting_writers: usize,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+/// RAII structure used to release the shared read access of a lock when dropped.
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    /// Not `Send`, like `MutexGuard`.
+    marker: PhantomData<*const ()>,
+}
+
+/// RAII structure used to release the exclusive write access of a lock when dropped.
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    /// Not `Send`, like `MutexGuard`.
+    marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> RwLock<T> {
+    /// Creates a new instance of an `RwLock<T>` which is unlocked.
+    pub fn new(data: T) -> Self {
+        RwLock {
+            state: spin::Mutex::new(State::default()),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this `RwLock`, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks this rwlock with shared read access, blocking the current thread until it can be acquired.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        wait_until(&self.state, &self.readers, |state| {
+            if state.writer || state.waiting_writers != 0 {
+                return None;
+            }
+            state.readers += 1;
+            enter_lock();
+            Some(())
+        });
+        RwLockReadGuard { lock: self, marker: PhantomData }
+    }
+
+    /// Locks this rwlock with exclusive write access, blocking the current thread until it can be acquired.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut waited: Option<(&ThreadPool, Tid)> = None;
+        loop {
+            let ret = no_interrupt(|| {
+                let mut state = self.state.lock();
+                if let Some((pool, tid)) = waited {
+                    // only sleeping writers are counted
+                    state.waiting_writers -= 1;
+                    let notified = !self.writers.remove(tid);
+                    if pool.cancel_pending(tid) {
+                        self.cancel_writer(&state, pool, notified);
+                        return Wait::Cancelled;
+                    }
+                }
+                if !state.writer && state.readers == 0 {
+                    state.writer = true;
+                    enter_lock();
+                    return Wait::Done(());
+                }
+                let (pool, tid) = current();
+                if pool.cancel_pending(tid) {
+                    return Wait::Cancelled;
+                }
+                state.waiting_writers += 1;
+                self.writers.wait(pool, tid);
+                waited = Some((pool, tid));
+                Wait::Block
+            });
+            match ret {
+                Wait::Done(()) => return RwLockWriteGuard { lock: self, marker: PhantomData },
+                Wait::Block => block(),
+                Wait::Cancelled => exit_cancelled(),
+            }
+        }
+    }
+
+    /// Called with `state` locked by a waiting writer going to exit,
+    /// after it's not counted as waiting.
+    /// Pass on the wakeup it took, if `notified`, or let readers in if it was the last writer.
+    fn cancel_writer(&self, state: &State, pool: &ThreadPool, notified: bool) {
+        if state.writer {
+            // the holder will notify the others
+            return;
+        }
+        let pass_on = notified && state.readers == 0 && state.waiting_writers != 0;
+        if pass_on && self.writers.notify_one(pool) {
+            return;
+        }
+        if state.waiting_writers == 0 {
+            self.readers.notify_all(pool);
+        }
+    }
+
+    /// Attempts to acquire this rwlock with shared read access without blocking.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let acquired = no_interrupt(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.waiting_writers != 0 {
+                return false;
+            }
+            state.readers += 1;
+            enter_lock();
+            true
+        });
+        if !acquired {
+            return None;
+        }
+        Some(RwLockReadGuard { lock: self, marker: PhantomData })
+    }
+
+    /// Attempts to lock this rwlock with exclusive write access without blocking.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let acquired = no_interrupt(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.readers != 0 {
+                return false;
+            }
+            state.writer = true;
+            enter_lock();
+            true
+        });
+        if !acquired {
+            return None;
+        }
+        Some(RwLockWriteGuard { lock: self, marker: PhantomData })
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn read_unlock(&self) {
+        no_interrupt(|| {
+            let mut state = self.state.lock();
+            state.readers -= 1;
+            if state.readers == 0 && state.waiting_writers != 0 {
+                self.writers.notify_one(processor().manager());
+            }
+        });
+    }
+
+    fn write_unlock(&self) {
+        no_interrupt(|| {
+            let mut state = self.state.lock();
+            state.writer = false;
+            let pool = processor().manager();
+            if state.waiting_writers != 0 && self.writers.notify_one(pool) {
+                return;
+            }
+            self.readers.notify_all(pool);
+        });
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+        leave_lock();
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+        leave_lock();
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::std_thread;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn killed_writer_lets_readers_in() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let lock = Arc::new(RwLock::new(0));
+        let read = Arc::new(AtomicBool::new(false));
+        let guard_taken = Arc::new(AtomicBool::new(false));
+        let holder = cpu.spawn({
+            let (lock, read, guard_taken) = (lock.clone(), read.clone(), guard_taken.clone());
+            move || {
+                let _guard = lock.read();
+                guard_taken.store(true, Ordering::SeqCst);
+                // the other reader gets in while the lock is still read
+                while !read.load(Ordering::SeqCst) {
+                    std_thread::yield_now();
+                }
+            }
+        });
+        let writer = cpu.spawn({
+            let (lock, guard_taken) = (lock.clone(), guard_taken.clone());
+            move || {
+                while !guard_taken.load(Ordering::SeqCst) {
+                    std_thread::yield_now();
+                }
+                *lock.write() += 1;
+                panic!("killed writer took the lock");
+            }
+        });
+        let reader = cpu.spawn({
+            let lock = lock.clone();
+            move || {
+                wait_sleeping(writer);
+                // blocked by the waiting writer
+                assert_eq!(*lock.read(), 0);
+                read.store(true, Ordering::SeqCst);
+            }
+        });
+        let killer = cpu.spawn(move || {
+            wait_sleeping(writer);
+            wait_sleeping(reader);
+            assert!(std_thread::processor().manager().kill(writer));
+        });
+        for &tid in [holder, writer, reader, killer].iter() {
+            cpu.join(tid);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! A counting semaphore putting waiters to sleep
+
+use super::*;
+
+/// A counting, blocking, semaphore
+///
+/// Useful for limiting access to a pool of resources.
+pub struct Semaphore {
+    count: spin::Mutex<usize>,
+    waiters: WaitQueue,
+}
+
+/// An RAII guard which will release a resource acquired from a semaphore when dropped.
+pub struct SemaphoreGuard<'a> {
+    sem: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with the initial count specified.
+    pub fn new(count: usize) -> Self {
+        Semaphore {
+            count: spin::Mutex::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Acquires a resource of this semaphore, blocking the current thread until it can do so.
+    pub fn acquire(&self) {
+        wait_until(&self.count, &self.waiters, |count| {
+            if *count == 0 {
+                return None;
+            }
+            *count -= 1;
+            Some(())
+        });
+    }
+
+    /// Tries to acquire a resource without blocking.
+    /// Return whether it succeeded.
+    pub fn try_acquire(&self) -> bool {
+        no_interrupt(|| {
+            let mut count = self.count.lock();
+            if *count == 0 {
+                return false;
+            }
+            *count -= 1;
+            true
+        })
+    }
+
+    /// Release a resource from this semaphore.
+    pub fn release(&self) {
+        no_interrupt(|| {
+            *self.count.lock() += 1;
+            notify_one(&self.waiters);
+        });
+    }
+
+    /// Acquires a resource of this semaphore, returning an RAII guard to release it.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        self.acquire();
+        SemaphoreGuard { sem: self }
+    }
+
+    /// Number of resources available now.
+    pub fn count(&self) -> usize {
+        no_interrupt(|| *self.count.lock())
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.sem.release();
+    }
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use crate::test_util::{wait_sleeping, TestCpu};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn try_acquire_and_release() {
+        let sem = Semaphore::new(2);
+        assert!(sem.try_acquire());
+        assert!(sem.try_acquire());
+        assert!(!sem.try_acquire());
+        sem.release();
+        assert_eq!(sem.count(), 1);
+        assert!(sem.try_acquire());
+        assert!(!sem.try_acquire());
+    }
+
+    #[test]
+    fn guard_releases() {
+        let sem = Semaphore::new(1);
+        {
+            let _guard = sem.access();
+            assert_eq!(sem.count(), 0);
+            assert!(!sem.try_acquire());
+        }
+        assert_eq!(sem.count(), 1);
+    }
+
+    #[test]
+    fn acquire_sleeps_until_release() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let sem = Arc::new(Semaphore::new(0));
+        let acquired = Arc::new(AtomicBool::new(false));
+        let waiter = cpu.spawn({
+            let (sem, acquired) = (sem.clone(), acquired.clone());
+            move || {
+                sem.acquire();
+                acquired.store(true, Ordering::SeqCst);
+            }
+        });
+        let releaser = cpu.spawn(move || {
+            wait_sleeping(waiter);
+            assert!(!acquired.load(Ordering::SeqCst));
+            sem.release();
+        });
+        cpu.join(releaser);
+        cpu.join(waiter);
+    }
+
+    #[test]
+    fn killed_waiter_passes_wakeup_on() {
+        let cpu = TestCpu::new(RRScheduler::new(5));
+        let sem = Arc::new(Semaphore::new(0));
+        let killed = cpu.spawn({
+            let sem = sem.clone();
+            move || {
+                sem.acquire();
+                panic!("killed waiter acquired");
+            }
+        });
+        let waiter = cpu.spawn({
+            let sem = sem.clone();
+            move || sem.acquire()
+        });
+        let releaser = cpu.spawn(move || {
+            wait_sleeping(killed);
+            wait_sleeping(waiter);
+            // the wakeup goes to the first waiter, which is then killed before it runs
+            sem.release();
+            assert!(crate::std_thread::processor().manager().kill(killed));
+        });
+        cpu.join(releaser);
+        cpu.join(killed);
+        cpu.join(waiter);
+    }
+}
@@ -0,0 +1,161 @@
+//! A userland CPU to test blocking for real
+//!
+//! Each thread of the `ThreadPool` runs on an OS thread of its own,
+//! but only the one holding the baton runs, so they take turns as on a single CPU.
+//! There is no timer interrupt: a thread runs until it blocks or yields.
+//!
+//! Threads spawned by `std_thread` run on OS threads too, see `new_kernel_context`.
+
+use crate::processor::Processor;
+use crate::scheduler::Scheduler;
+use crate::std_thread;
+use crate::thread_pool::{Context, Status, ThreadPool, Tid};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex};
+use std::time::Instant;
+
+std::thread_local! {
+    /// The `Processor` the current OS thread runs on.
+    static PROCESSOR: Cell<Option<&'static Processor>> = Cell::new(None);
+}
+
+/// Used by `std_thread::processor` in tests.
+pub fn processor() -> &'static Processor {
+    PROCESSOR
+        .with(|processor| processor.get())
+        .expect("not running on a TestCpu")
+}
+
+/// Used by `std_thread::new_kernel_context` in tests.
+///
+/// A panic can't unwind out of `entry`, so it aborts the tests.
+pub fn new_kernel_context(entry: extern "C" fn(usize) -> !, arg: usize) -> Box<dyn Context> {
+    let baton = Arc::new(Baton::default());
+    let waiting = baton.clone();
+    std::thread::spawn(move || {
+        waiting.take();
+        entry(arg)
+    });
+    Box::new(OsContext { baton })
+}
+
+/// A context owning an OS thread, which runs only after the baton is passed to it.
+struct OsContext {
+    baton: Arc<Baton>,
+}
+
+#[derive(Default)]
+struct Baton {
+    /// Passed to this context, with the `Processor` to run on.
+    passed: Mutex<Option<&'static Processor>>,
+    cond: Condvar,
+}
+
+impl Baton {
+    fn pass(&self, processor: &'static Processor) {
+        *self.passed.lock().unwrap() = Some(processor);
+        self.cond.notify_one();
+    }
+
+    /// Block the current OS thread until the baton is passed to it.
+    fn take(&self) {
+        let mut passed = self.passed.lock().unwrap();
+        loop {
+            if let Some(processor) = passed.take() {
+                PROCESSOR.with(|current| current.set(Some(processor)));
+                return;
+            }
+            passed = self.cond.wait(passed).unwrap();
+        }
+    }
+}
+
+impl Context for OsContext {
+    unsafe fn switch_to(&mut self, target: &mut dyn Context) {
+        // all contexts on a `TestCpu` are `OsContext`
+        let target = &*(target as *mut dyn Context as *mut OsContext);
+        // an exiting thread's context may be dropped as soon as the baton is passed
+        let baton = self.baton.clone();
+        target.baton.pass(processor());
+        baton.take();
+    }
+}
+
+/// A CPU running the threads of its own `ThreadPool`.
+///
+/// It idles forever after the test, as a `Processor` never stops.
+pub struct TestCpu {
+    pool: Arc<ThreadPool>,
+    panicked: Arc<AtomicBool>,
+}
+
+impl TestCpu {
+    pub fn new(scheduler: impl Scheduler) -> Self {
+        let pool = Arc::new(ThreadPool::unbounded(scheduler));
+        let processor: &'static Processor = Box::leak(Box::new(Processor::new()));
+        let context = OsContext {
+            baton: Arc::new(Baton::default()),
+        };
+        unsafe {
+            processor.init(0, Box::new(context), pool.clone());
+        }
+        std::thread::spawn(move || {
+            PROCESSOR.with(|current| current.set(Some(processor)));
+            processor.run()
+        });
+        TestCpu {
+            pool,
+            panicked: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Add a thread running `f`.
+    pub fn spawn(&self, f: impl FnOnce() + Send + 'static) -> Tid {
+        let baton = Arc::new(Baton::default());
+        let waiting = baton.clone();
+        let panicked = self.panicked.clone();
+        std::thread::spawn(move || {
+            waiting.take();
+            if panic::catch_unwind(AssertUnwindSafe(f)).is_err() {
+                panicked.store(true, Ordering::SeqCst);
+            }
+            let processor = processor();
+            processor.manager().exit(processor.tid(), 0);
+            std_thread::yield_now();
+            unreachable!()
+        });
+        self.pool.add(Box::new(OsContext { baton }))
+    }
+
+    /// Wait for thread `tid` to exit.
+    ///
+    /// Panics if any thread panicked, or `tid` doesn't exit in time, e.g. on a deadlock.
+    pub fn join(&self, tid: Tid) {
+        let start = Instant::now();
+        loop {
+            assert!(!self.panicked.load(Ordering::SeqCst), "a thread panicked");
+            match self.pool.status(tid) {
+                Some(Status::Exited(_)) | None => return,
+                _ => {}
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "thread {} never exits",
+                tid
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Called by a thread on a `TestCpu`: yield until thread `tid` sleeps, e.g. blocked on a lock.
+pub fn wait_sleeping(tid: Tid) {
+    while processor().manager().status(tid) != Some(Status::Sleeping) {
+        std_thread::yield_now();
+    }
+}
@@ -1,25 +1,105 @@
-use crate::scheduler::Scheduler;
-use crate::timer::Timer;
+use crate::scheduler::{Reservation, ReserveError, Scheduler};
+use crate::thread_table::{tid_index, ThreadTable};
+use crate::timer::{self, Timer};
+use crate::wait_queue::WaitQueue;
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
 use log::*;
-use spin::{Mutex, MutexGuard};
+use spin::{Mutex, MutexGuard, Once};
 
 struct Thread {
+    /// The id of the thread, including the generation of its slot.
+    tid: Tid,
+    /// The name of the thread, shown in logs.
+    name: Option<String>,
+    /// CPUs the thread may run on, as a bit mask. 0 means all.
+    affinity: usize,
     /// Current status of the thread.
     status: Status,
     /// Next status after the thread stop running.
     status_after_stop: Status,
-    /// A waiter thread of this. It will be woken up on my exit.
-    waiter: Option<Tid>,
+    /// Threads waiting for this. They will be woken up on my exit.
+    joiners: WaitQueue,
     /// If detached, all resources will be released on exit.
     detached: bool,
     /// The context of the thread.
     context: Option<Box<dyn Context>>,
     // The priority of the thread.
     priority: u8,
+    /// The priority seen by the scheduler.
+    /// Higher than `priority` when inherited from the waiters of PI locks it holds.
+    effective_priority: u8,
+    /// Priorities inherited through each PI lock held: (lock id, priority).
+    inherited: Vec<(usize, u8)>,
+    /// The PI lock this thread is blocked on, and its owner: (lock id, owner).
+    blocked_on: Option<(usize, Tid)>,
+    /// Thread local values, keyed by the address of their `LocalKey`.
+    locals: BTreeMap<usize, Box<dyn Any>>,
+    /// How the thread exited, if not by returning.
+    abort: Option<Abort>,
+    /// Killed, and going to exit at the next cancellation point. See `ThreadPool::kill`.
+    killed: bool,
+    /// Nesting depth of sections deferring the cancellation. See `ThreadPool::disable_cancel`.
+    no_cancel: usize,
+    /// Nesting depth of `std_thread::scope`s. Its panics are not handled in them.
+    scopes: usize,
+    /// The park token. See `ThreadPool::park`.
+    park_token: bool,
+    /// Sleeping in `ThreadPool::park`, so `unpark` should wake it up.
+    parked: bool,
+    /// The timer to wake it up, if sleeping for a while: (CPU of the timer, handle).
+    wakeup_timer: Option<(usize, timer::TimerHandle)>,
+    /// The CPU it runs or last ran on.
+    cpu: usize,
+    /// CPU accounting, up to tick `since`.
+    stats: ThreadStats,
+    /// The tick its current status began at, or accounted up to.
+    since: usize,
 }
 
+/// Whether a thread of `affinity` may run on CPU `cpu_id`.
+fn runs_on(affinity: usize, cpu_id: usize) -> bool {
+    let bits = core::mem::size_of::<usize>() * 8;
+    affinity == 0 || cpu_id >= bits || affinity & (1 << cpu_id) != 0
+}
+
+impl Thread {
+    /// Charge the ticks since the last accounting to its current status.
+    /// Call it at `now` before changing the status.
+    fn account(&mut self, now: usize) {
+        let ticks = now.wrapping_sub(self.since);
+        self.since = now;
+        match self.status {
+            Status::Running(_) => self.stats.run_ticks += ticks,
+            Status::Ready => self.stats.ready_ticks += ticks,
+            Status::Sleeping => self.stats.sleep_ticks += ticks,
+            Status::Exited(_) => {}
+        }
+    }
+}
+
+impl fmt::Display for Thread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}({})", self.tid, name),
+            None => write!(f, "{}", self.tid),
+        }
+    }
+}
+
+/// Thread id.
+///
+/// The low half is the index of the thread's slot in the `ThreadPool`,
+/// the high half is a generation bumped each time the slot is reused.
+/// So a `Tid` held after the thread is removed never refers to another thread.
 pub type Tid = usize;
 type ExitCode = usize;
 
@@ -35,6 +115,27 @@ pub enum Status {
 #[derive(Eq, PartialEq)]
 enum Event {
     Wakeup(Tid),
+    /// Run the kernel timer of the id, if it's still armed by the sequence number.
+    Callback(usize, usize),
+}
+
+/// A handle to a kernel timer. See `ThreadPool::add_timer`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct TimerHandle(usize);
+
+struct KernelTimer {
+    /// The CPU whose timer queue it's armed in.
+    cpu: usize,
+    /// The tick it expires at.
+    deadline: usize,
+    /// Interval in ticks of a periodic timer.
+    period: Option<usize>,
+    /// Taken out while it's running.
+    callback: Option<Box<dyn FnMut() + Send>>,
+    /// Bumped each time it's armed, to tell stale events.
+    seq: usize,
+    /// The event in the timer queue. `None` after it expires.
+    armed: Option<(usize, timer::TimerHandle)>,
 }
 
 pub trait Context {
@@ -46,206 +147,1147 @@ pub trait Context {
     fn set_tid(&mut self, _tid: Tid) {}
 }
 
+/// The timer hardware, to run in tickless mode. See `ThreadPool::with_clock_event`.
+///
+/// Instead of a periodic tick, each CPU gets a one-shot timer interrupt programmed
+/// at its next timer event, or the end of the time slice of the thread running on it.
+/// The interrupt handler calls `Processor::tick` as usual.
+///
+/// An idle CPU only wakes up on its own deadline or other interrupts.
+/// A thread woken up by another CPU waits until some CPU reschedules,
+/// so the embedder may want to send an IPI to idle CPUs then.
+pub trait ClockEvent: Send + Sync + 'static {
+    /// Ticks elapsed on a monotonic clock shared by all CPUs.
+    /// Its origin doesn't matter, but it must be counted at the rate of `ThreadPool::tick_rate`.
+    fn ticks(&self) -> usize;
+    /// Program a one-shot timer interrupt on the current CPU `cpu_id`
+    /// after `ticks` ticks (0 means as soon as possible), replacing the previous one.
+    /// `None` means no interrupt is needed.
+    ///
+    /// `ThreadPool::add_timer` may call it for another CPU. An embedder unable to
+    /// program a remote CPU may ignore it, delaying the timer to its next interrupt.
+    fn set_next_event(&self, cpu_id: usize, ticks: Option<usize>);
+}
+
+/// Attributes of a new thread. See `ThreadPool::try_add_with`.
+#[derive(Debug, Clone)]
+pub struct ThreadAttr {
+    /// The name of the thread, shown in logs.
+    pub name: Option<String>,
+    /// The priority of the thread. Default to 1.
+    pub priority: u8,
+    /// CPUs the thread may run on, as a bit mask. Default to 0, meaning all.
+    pub affinity: usize,
+    /// Release all resources on exit, as if `detach` is called. Default to false.
+    pub detached: bool,
+}
+
+impl Default for ThreadAttr {
+    fn default() -> Self {
+        ThreadAttr {
+            name: None,
+            priority: 1,
+            affinity: 0,
+            detached: false,
+        }
+    }
+}
+
+/// How a thread exited, if not by returning. See `ThreadPool::try_join`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Abort {
+    /// Panicked with the message. See `ThreadPool::exit_on_panic`.
+    Panicked(String),
+    /// Cancelled by `ThreadPool::kill`.
+    Killed,
+}
+
+/// CPU accounting of a thread. See `ThreadPool::thread_stats`.
+///
+/// Times are counted in ticks of the clock, so they are as coarse as a tick.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ThreadStats {
+    /// Ticks spent running.
+    pub run_ticks: usize,
+    /// Ticks spent ready, waiting for a CPU.
+    pub ready_ticks: usize,
+    /// Ticks spent sleeping, e.g. blocked on a lock or in `sleep`.
+    pub sleep_ticks: usize,
+    /// Times it switched out to sleep.
+    pub voluntary_switches: usize,
+    /// Times it switched out still ready, i.e. preempted or yielded.
+    pub involuntary_switches: usize,
+    /// The CPU it runs or last ran on. `None` if it has never run.
+    pub last_cpu: Option<usize>,
+}
+
+/// Why a sleeping thread sleeps, as far as the pool knows. See `ThreadPool::snapshot`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WaitReason {
+    /// Blocked on the PI lock of the id, held by `owner`.
+    PiLock { lock: usize, owner: Tid },
+    /// Parked. See `ThreadPool::park`.
+    Park,
+    /// Joining the thread.
+    Join(Tid),
+    /// Anything else, e.g. sleeping for a while, or waiting on a lock or channel.
+    Other,
+}
+
+/// A thread seen by `ThreadPool::snapshot`.
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub tid: Tid,
+    pub name: Option<String>,
+    /// The status, including which CPU it's running on.
+    pub status: Status,
+    /// The base priority.
+    pub priority: u8,
+    /// The priority seen by the scheduler, maybe inherited from a PI lock.
+    pub effective_priority: u8,
+    /// CPUs it may run on, as a bit mask. 0 means all.
+    pub affinity: usize,
+    pub detached: bool,
+    /// Killed, but not exited yet.
+    pub killed: bool,
+    /// Why it's sleeping. `None` if it's not.
+    pub wait_reason: Option<WaitReason>,
+    /// Threads waiting to join it.
+    pub joiners: Vec<Tid>,
+    pub stats: ThreadStats,
+}
+
+/// Display a snapshot as a `ps`-like table, one thread per line:
+///
+/// ```ignore
+/// write!(out, "{}", ThreadList(&pool.snapshot()))?;
+/// ```
+pub struct ThreadList<'a>(pub &'a [ThreadInfo]);
+
+impl fmt::Display for ThreadList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>8} {:<16} {:<8} {:>3} {:>7} {:>8} {:>8} {:>8} {:>6} {:>6} {:<2} WAIT",
+            "TID", "NAME", "STATUS", "CPU", "PRI", "RUN", "READY", "SLEEP", "VCSW", "ICSW", "FL"
+        )?;
+        for info in self.0 {
+            let status = match info.status {
+                Status::Ready => "ready",
+                Status::Running(_) => "running",
+                Status::Sleeping => "sleeping",
+                Status::Exited(_) => "exited",
+            };
+            let cpu = match info.stats.last_cpu {
+                Some(cpu) => format!("{}", cpu),
+                None => String::from("-"),
+            };
+            let priority = if info.effective_priority == info.priority {
+                format!("{}", info.priority)
+            } else {
+                format!("{}({})", info.effective_priority, info.priority)
+            };
+            let flags = match (info.detached, info.killed) {
+                (true, true) => "DK",
+                (true, false) => "D",
+                (false, true) => "K",
+                (false, false) => "-",
+            };
+            let wait = match info.wait_reason {
+                Some(WaitReason::PiLock { owner, .. }) => format!("lock {}", owner),
+                Some(WaitReason::Park) => String::from("park"),
+                Some(WaitReason::Join(target)) => format!("join {}", target),
+                Some(WaitReason::Other) => String::from("wait"),
+                None => String::from("-"),
+            };
+            writeln!(
+                f,
+                "{:>8} {:<16} {:<8} {:>3} {:>7} {:>8} {:>8} {:>8} {:>6} {:>6} {:<2} {}",
+                info.tid,
+                info.name.as_ref().map_or("-", |name| name.as_str()),
+                status,
+                cpu,
+                priority,
+                info.stats.run_ticks,
+                info.stats.ready_ticks,
+                info.stats.sleep_ticks,
+                info.stats.voluntary_switches,
+                info.stats.involuntary_switches,
+                flags,
+                wait
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of per-CPU timer queues.
+/// CPUs with larger ids share them, as the bit mask of affinity can't tell them apart either.
+const MAX_CPUS: usize = core::mem::size_of::<usize>() * 8;
+
+/// Default frequency of timer ticks, in Hz.
+const DEFAULT_TICK_RATE: u32 = 100;
+
+/// Errors returned by the fallible `ThreadPool` operations.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ThreadError {
+    /// The thread table reached its hard limit.
+    /// A kernel should report it as `EAGAIN`.
+    Exhausted,
+}
+
 pub struct ThreadPool {
-    threads: Vec<Mutex<Option<Thread>>>,
+    threads: ThreadTable<Thread>,
     scheduler: Box<dyn Scheduler>,
-    timer: Mutex<Timer<Event>>,
+    /// Timer queues of each CPU, allocated on first use.
+    timers: Vec<Once<Mutex<Timer<Event>>>>,
+    /// Frequency of timer ticks, in Hz.
+    tick_rate: u32,
+    /// Ticks since the pool is created: the most advanced of the timer queues.
+    /// Not used in tickless mode.
+    clock: AtomicUsize,
+    /// The timer hardware in tickless mode.
+    clock_event: Option<Box<dyn ClockEvent>>,
+    /// The tick each CPU's next interrupt is programmed at in tickless mode,
+    /// `usize::MAX` for none.
+    programmed: Vec<AtomicUsize>,
+    /// The tick the thread running on each CPU is charged up to in tickless mode.
+    /// Reset when a thread is switched in, so it's not charged for the idle time before.
+    charged: Vec<AtomicUsize>,
+    /// Kernel timers by the id of their handles.
+    kernel_timers: Mutex<BTreeMap<usize, KernelTimer>>,
+    next_timer_id: AtomicUsize,
+    /// Affinity of the threads not allowed on all CPUs, by slot index.
+    /// Held across the scheduler's pop in `run`, so it's taken after thread locks
+    /// and before the scheduler lock.
+    pinned: Mutex<BTreeMap<usize, usize>>,
 }
 
 impl ThreadPool {
+    /// Create a pool holding at most `max_proc_num` threads.
+    ///
+    /// The thread table grows on demand up to this limit.
     pub fn new(scheduler: impl Scheduler, max_proc_num: usize) -> Self {
         ThreadPool {
-            threads: new_vec_default(max_proc_num),
+            threads: ThreadTable::new(max_proc_num),
             scheduler: Box::new(scheduler),
-            timer: Mutex::new(Timer::new()),
+            timers: (0..MAX_CPUS).map(|_| Once::new()).collect(),
+            tick_rate: DEFAULT_TICK_RATE,
+            clock: AtomicUsize::new(0),
+            clock_event: None,
+            programmed: (0..MAX_CPUS)
+                .map(|_| AtomicUsize::new(usize::max_value()))
+                .collect(),
+            charged: (0..MAX_CPUS).map(|_| AtomicUsize::new(0)).collect(),
+            kernel_timers: Mutex::new(BTreeMap::new()),
+            next_timer_id: AtomicUsize::new(0),
+            pinned: Mutex::new(BTreeMap::new()),
         }
     }
 
-    fn alloc_tid(&self) -> (Tid, MutexGuard<Option<Thread>>) {
-        for (i, proc) in self.threads.iter().enumerate() {
-            let thread = proc.lock();
-            if thread.is_none() {
-                return (i, thread);
-            }
+    /// Run in tickless mode, programming one-shot timer interrupts by `clock`.
+    /// See `ClockEvent`.
+    pub fn with_clock_event(mut self, clock: impl ClockEvent) -> Self {
+        self.clock_event = Some(Box::new(clock));
+        self
+    }
+
+    /// Set the frequency of `Processor::tick` being called on each CPU, in Hz.
+    /// Default to 100.
+    ///
+    /// Durations, e.g. of `std_thread::sleep`, are converted to ticks by it.
+    pub fn with_tick_rate(mut self, hz: u32) -> Self {
+        assert_ne!(hz, 0, "tick rate must be nonzero");
+        self.tick_rate = hz;
+        self
+    }
+
+    /// Frequency of timer ticks, in Hz.
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    /// The monotonic clock: time since the pool is created, in the resolution of a tick.
+    pub fn now(&self) -> Duration {
+        self.ticks_to_dur(self.ticks())
+    }
+
+    /// The current tick of the monotonic clock.
+    fn ticks(&self) -> usize {
+        match &self.clock_event {
+            Some(clock) => clock.ticks(),
+            None => self.clock.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Convert a duration to timer ticks, rounding up.
+    /// So a nonzero duration is at least one tick.
+    pub fn dur_to_ticks(&self, dur: Duration) -> usize {
+        let hz = u128::from(self.tick_rate);
+        let ticks = (dur.as_nanos() * hz + 999_999_999) / 1_000_000_000;
+        if ticks > usize::max_value() as u128 {
+            usize::max_value()
+        } else {
+            ticks as usize
+        }
+    }
+
+    /// Convert timer ticks to a duration.
+    pub fn ticks_to_dur(&self, ticks: usize) -> Duration {
+        let hz = self.tick_rate as u64;
+        let ticks = ticks as u64;
+        let nanos = (ticks % hz) * 1_000_000_000 / hz;
+        Duration::new(ticks / hz, nanos as u32)
+    }
+
+    /// Create a pool without a limit on the number of threads.
+    pub fn unbounded(scheduler: impl Scheduler) -> Self {
+        Self::new(scheduler, usize::max_value())
+    }
+
+    fn alloc_tid(&self) -> Result<(Tid, MutexGuard<'_, Option<Thread>>), ThreadError> {
+        self.threads.alloc().ok_or(ThreadError::Exhausted)
+    }
+
+    /// The timer queue of CPU `cpu_id`.
+    ///
+    /// All queues run at the time of the clock. A CPU ticking late may lag behind a bit.
+    fn timer(&self, cpu_id: usize) -> &Mutex<Timer<Event>> {
+        self.timers[cpu_id % MAX_CPUS].call_once(|| {
+            let mut timer = Timer::new();
+            timer.advance_to(self.ticks());
+            Mutex::new(timer)
+        })
+    }
+
+    /// Lock the slot of thread `tid`.
+    /// Return `None` if `tid` is stale, i.e. the thread has been removed.
+    fn lock(&self, tid: Tid) -> Option<MutexGuard<'_, Option<Thread>>> {
+        let proc_lock = self.threads.get(tid_index(tid))?.lock();
+        match proc_lock.as_ref() {
+            Some(proc) if proc.tid == tid => Some(proc_lock),
+            _ => None,
+        }
+    }
+
+    /// Like `lock`, but return `None` if the thread is locked now.
+    fn try_lock(&self, tid: Tid) -> Option<MutexGuard<'_, Option<Thread>>> {
+        let proc_lock = self.threads.get(tid_index(tid))?.try_lock()?;
+        match proc_lock.as_ref() {
+            Some(proc) if proc.tid == tid => Some(proc_lock),
+            _ => None,
         }
-        panic!("Thread number exceeded");
     }
 
     /// Add a new thread
     /// Calls action with tid and thread context
-    pub fn add(&self, mut context: Box<dyn Context>) -> Tid {
-        let (tid, mut thread) = self.alloc_tid();
-        context.set_tid(tid);
-        *thread = Some(Thread {
-            status: Status::Ready,
-            status_after_stop: Status::Ready,
-            waiter: None,
-            detached: false,
-            context: Some(context),
-            priority: 1,
-        });
-        self.scheduler.set_priority(tid, 1);
-        self.scheduler.push(tid);
-        tid
+    ///
+    /// Panics if the thread table is full. See `try_add`.
+    pub fn add(&self, context: Box<dyn Context>) -> Tid {
+        self.try_add(context).expect("Thread number exceeded")
     }
 
+    /// Add a new thread, or return an error if the thread table is full.
+    pub fn try_add(&self, context: Box<dyn Context>) -> Result<Tid, ThreadError> {
+        self.try_add_pri(context, 1)
+    }
 
     /// Add a new thread with special priority
     /// Calls action with tid and thread context
-    pub fn add_pri(&self, mut context: Box<dyn Context>, priority: u8) -> Tid {
-        let (tid, mut thread) = self.alloc_tid();
+    ///
+    /// Panics if the thread table is full. See `try_add_pri`.
+    pub fn add_pri(&self, context: Box<dyn Context>, priority: u8) -> Tid {
+        self.try_add_pri(context, priority)
+            .expect("Thread number exceeded")
+    }
+
+    /// Add a new thread with special priority,
+    /// or return an error if the thread table is full.
+    pub fn try_add_pri(&self, context: Box<dyn Context>, priority: u8) -> Result<Tid, ThreadError> {
+        let attr = ThreadAttr {
+            priority,
+            ..ThreadAttr::default()
+        };
+        self.try_add_with(context, attr)
+    }
+
+    /// Add a new thread with attributes,
+    /// or return an error if the thread table is full.
+    pub fn try_add_with(
+        &self,
+        mut context: Box<dyn Context>,
+        attr: ThreadAttr,
+    ) -> Result<Tid, ThreadError> {
+        let priority = attr.priority;
+        let (tid, mut thread) = self.alloc_tid()?;
         let pri_temp = self.scheduler.cal_priority(priority);
         context.set_tid(tid);
         *thread = Some(Thread {
+            tid,
+            name: attr.name,
+            affinity: attr.affinity,
             status: Status::Ready,
             status_after_stop: Status::Ready,
-            waiter: None,
-            detached: false,
+            joiners: WaitQueue::new(),
+            detached: attr.detached,
             context: Some(context),
             priority: pri_temp,
+            effective_priority: pri_temp,
+            inherited: Vec::new(),
+            blocked_on: None,
+            locals: BTreeMap::new(),
+            abort: None,
+            killed: false,
+            no_cancel: 0,
+            scopes: 0,
+            park_token: false,
+            parked: false,
+            wakeup_timer: None,
+            cpu: 0,
+            stats: ThreadStats::default(),
+            since: self.ticks(),
         });
-        self.scheduler.set_priority(tid, priority);
-        self.scheduler.push(tid);
-        tid
+        trace!("thread {} added", thread.as_ref().unwrap());
+        let mut pinned = self.pinned.lock();
+        if attr.affinity == 0 {
+            pinned.remove(&tid_index(tid));
+        } else {
+            pinned.insert(tid_index(tid), attr.affinity);
+        }
+        drop(pinned);
+        self.scheduler.set_priority(tid_index(tid), priority);
+        self.scheduler.push(tid_index(tid));
+        Ok(tid)
     }
 
-
     /// Make thread `tid` time slice -= 1.
     /// Return true if time slice == 0.
     /// Called by timer interrupt handler.
+    ///
+    /// Every CPU drives its own timer queue here.
+    ///
+    /// In tickless mode, it's called on the one-shot interrupts, which may come early or late.
+    /// The running thread is charged all the ticks elapsed since the last call,
+    /// or since it was switched in.
     pub(crate) fn tick(&self, cpu_id: usize, tid: Option<Tid>) -> bool {
-        if cpu_id == 0 {
-            let mut timer = self.timer.lock();
-            timer.tick();
+        // Collect expired events first, so the timer is not locked
+        // when handling them. (`wakeup` needs to lock it)
+        let mut events = Vec::new();
+        let now = {
+            let mut timer = self.timer(cpu_id).lock();
+            match &self.clock_event {
+                Some(clock) => timer.advance_to(clock.ticks()),
+                None => timer.tick(),
+            }
+            self.clock.fetch_max(timer.now(), Ordering::Relaxed);
             while let Some(event) = timer.pop() {
-                match event {
-                    Event::Wakeup(tid) => self.set_status(tid, Status::Ready),
+                events.push(event);
+            }
+            timer.now()
+        };
+        let elapsed = match &self.clock_event {
+            Some(_) => {
+                let charged = self.charged[cpu_id % MAX_CPUS].swap(now, Ordering::Relaxed);
+                now.wrapping_sub(charged)
+            }
+            None => 1,
+        };
+        self.scheduler.set_time(self.ticks());
+        for event in events {
+            match event {
+                Event::Wakeup(tid) => {
+                    self.wakeup(tid);
+                }
+                Event::Callback(id, seq) => {
+                    self.run_timer(id, seq);
                 }
             }
         }
-        match tid {
-            Some(tid) => self.scheduler.tick(tid),
+        let need_reschedule = match tid {
+            Some(tid) => self.scheduler.tick_n(tid_index(tid), elapsed),
             None => false,
+        };
+        if !need_reschedule {
+            self.program_next_event(cpu_id, tid);
+        }
+        need_reschedule
+    }
+
+    /// In tickless mode, program the next timer interrupt of CPU `cpu_id`
+    /// at its next timer event, or the end of the time slice of `tid` running on it.
+    pub(crate) fn program_next_event(&self, cpu_id: usize, tid: Option<Tid>) {
+        let clock = match &self.clock_event {
+            Some(clock) => clock,
+            None => return,
+        };
+        let deadline = self.next_deadline(cpu_id);
+        let slice = tid.map(|tid| self.scheduler.remaining_slice(tid_index(tid)));
+        let next = match (deadline, slice) {
+            (Some(deadline), Some(slice)) => Some(deadline.min(slice)),
+            (deadline, slice) => deadline.or(slice),
+        };
+        let at = next.map_or(usize::max_value(), |next| self.ticks().wrapping_add(next));
+        self.programmed[cpu_id % MAX_CPUS].store(at, Ordering::Relaxed);
+        clock.set_next_event(cpu_id, next);
+    }
+
+    /// In tickless mode, make sure CPU `cpu_id` is interrupted by tick `deadline`,
+    /// programming its interrupt earlier if needed.
+    fn program_deadline(&self, cpu_id: usize, deadline: usize) {
+        let clock = match &self.clock_event {
+            Some(clock) => clock,
+            None => return,
+        };
+        let now = self.ticks();
+        let programmed = &self.programmed[cpu_id % MAX_CPUS];
+        let ahead = match programmed.load(Ordering::Relaxed) {
+            at if at == usize::max_value() => usize::max_value(),
+            at => at.wrapping_sub(now),
+        };
+        let ticks = match deadline.wrapping_sub(now) {
+            // passed
+            ticks if ticks > usize::max_value() / 2 => 0,
+            ticks => ticks,
+        };
+        if ticks < ahead {
+            programmed.store(now.wrapping_add(ticks), Ordering::Relaxed);
+            clock.set_next_event(cpu_id, Some(ticks));
+        }
+    }
+
+    /// Number of ticks until the next timer event on CPU `cpu_id`,
+    /// e.g. waking up a thread slept on it. Return `None` if there is none.
+    ///
+    /// A CPU going idle may use it to skip the ticks in between.
+    pub fn next_deadline(&self, cpu_id: usize) -> Option<usize> {
+        let deadline = self.timer(cpu_id).lock().next_deadline()?;
+        // the queue may lag behind the clock
+        Some(deadline.saturating_sub(self.ticks()))
+    }
+
+    /// Move the timers of CPU `from` to CPU `to`.
+    ///
+    /// Call it when CPU `from` goes offline and stops ticking,
+    /// so the threads slept on it still wake up in time.
+    pub fn migrate_timers(&self, from: usize, to: usize) {
+        if from % MAX_CPUS == to % MAX_CPUS {
+            return;
+        }
+        let timers = self.timer(from).lock().drain();
+        for (handle, time, event) in timers {
+            match event {
+                Event::Wakeup(tid) => {
+                    let mut proc_lock = match self.lock(tid) {
+                        Some(proc_lock) => proc_lock,
+                        None => continue,
+                    };
+                    let proc = proc_lock.as_mut().unwrap();
+                    // not cancelled in the meantime
+                    if proc.wakeup_timer != Some((from, handle)) {
+                        continue;
+                    }
+                    let handle = self.timer(to).lock().start_at(time, Event::Wakeup(tid));
+                    proc.wakeup_timer = Some((to, handle));
+                }
+                Event::Callback(id, seq) => {
+                    let mut timers = self.kernel_timers.lock();
+                    let entry = match timers.get_mut(&id) {
+                        Some(entry) => entry,
+                        None => continue,
+                    };
+                    // not cancelled or modified in the meantime
+                    if entry.armed != Some((from, handle)) {
+                        continue;
+                    }
+                    let handle = self.timer(to).lock().start_at(time, Event::Callback(id, seq));
+                    entry.cpu = to;
+                    entry.armed = Some((to, handle));
+                    drop(timers);
+                    self.program_deadline(to, time);
+                }
+            }
+        }
+    }
+
+    /// Run `callback` on CPU `cpu_id` after `delay`. Return a handle to cancel or modify it.
+    ///
+    /// # Context
+    ///
+    /// The callback runs in the timer interrupt, in `Processor::tick` of CPU `cpu_id`
+    /// with interrupts disabled, after the threads due on the tick are woken up.
+    /// So it must be short, and must not block, sleep or yield:
+    /// no `sync::Mutex`, `std_thread::sleep` or channel `recv`.
+    /// Spin locks taken with interrupts disabled are fine.
+    /// For heavier work, wake up or `unpark` a worker thread, or `send` on a channel.
+    ///
+    /// It may add, modify or cancel timers, including its own.
+    ///
+    /// The delay is rounded up to ticks. A zero delay runs it on the next tick.
+    /// In tickless mode, the interrupt of CPU `cpu_id` is programmed earlier if needed.
+    pub fn add_timer(
+        &self,
+        cpu_id: usize,
+        delay: Duration,
+        callback: impl FnMut() + Send + 'static,
+    ) -> TimerHandle {
+        self.insert_timer(cpu_id, self.dur_to_ticks(delay), None, Box::new(callback))
+    }
+
+    /// Run `callback` on CPU `cpu_id` every `period`, first after a period.
+    ///
+    /// Run in the same context as `add_timer`. Missed periods are skipped rather than
+    /// run back-to-back, e.g. when the CPU was stopped or the callback ran too long.
+    pub fn add_periodic_timer(
+        &self,
+        cpu_id: usize,
+        period: Duration,
+        callback: impl FnMut() + Send + 'static,
+    ) -> TimerHandle {
+        let period = self.dur_to_ticks(period).max(1);
+        self.insert_timer(cpu_id, period, Some(period), Box::new(callback))
+    }
+
+    fn insert_timer(
+        &self,
+        cpu_id: usize,
+        delay: usize,
+        period: Option<usize>,
+        callback: Box<dyn FnMut() + Send>,
+    ) -> TimerHandle {
+        let id = self.next_timer_id.fetch_add(1, Ordering::Relaxed);
+        let mut timer = KernelTimer {
+            cpu: cpu_id,
+            deadline: 0,
+            period,
+            callback: Some(callback),
+            seq: 0,
+            armed: None,
+        };
+        let deadline = self.ticks().wrapping_add(delay);
+        let mut timers = self.kernel_timers.lock();
+        self.arm_timer(id, &mut timer, deadline);
+        timers.insert(id, timer);
+        drop(timers);
+        self.program_deadline(cpu_id, deadline);
+        TimerHandle(id)
+    }
+
+    /// Restart the timer of `handle` to expire after `delay` from now.
+    /// A periodic timer keeps its period from then on.
+    ///
+    /// It works while the callback is running too, e.g. for a one-shot timer to re-arm itself.
+    ///
+    /// Return false if the timer has been cancelled, or has expired and finished.
+    pub fn modify_timer(&self, handle: TimerHandle, delay: Duration) -> bool {
+        let delay = self.dur_to_ticks(delay);
+        let mut timers = self.kernel_timers.lock();
+        let timer = match timers.get_mut(&handle.0) {
+            Some(timer) => timer,
+            None => return false,
+        };
+        let deadline = self.ticks().wrapping_add(delay);
+        self.arm_timer(handle.0, timer, deadline);
+        let cpu_id = timer.cpu;
+        drop(timers);
+        self.program_deadline(cpu_id, deadline);
+        true
+    }
+
+    /// Cancel the timer of `handle`.
+    ///
+    /// Return true if this stops its callback from running again,
+    /// false if it has finished or been cancelled already.
+    /// A callback running on another CPU at this time is not waited for.
+    pub fn cancel_timer(&self, handle: TimerHandle) -> bool {
+        let timer = self.kernel_timers.lock().remove(&handle.0);
+        match timer {
+            Some(mut timer) => {
+                if let Some((cpu_id, event)) = timer.armed.take() {
+                    self.timer(cpu_id).lock().cancel(event);
+                    true
+                } else {
+                    // running now, and not re-armed
+                    timer.period.is_some()
+                }
+            }
+            None => false,
+        }
+        // the callback is dropped here, out of the lock
+    }
+
+    /// Arm `timer` of `id` at tick `deadline` on its CPU, replacing its pending event.
+    /// The table of kernel timers should be locked.
+    fn arm_timer(&self, id: usize, timer: &mut KernelTimer, deadline: usize) {
+        if let Some((cpu_id, event)) = timer.armed.take() {
+            self.timer(cpu_id).lock().cancel(event);
+        }
+        timer.seq = timer.seq.wrapping_add(1);
+        timer.deadline = deadline;
+        let event = self
+            .timer(timer.cpu)
+            .lock()
+            .start_at(deadline, Event::Callback(id, timer.seq));
+        timer.armed = Some((timer.cpu, event));
+    }
+
+    /// Run the kernel timer `id` expired on the current CPU, then re-arm or remove it.
+    fn run_timer(&self, id: usize, seq: usize) {
+        let mut callback = {
+            let mut timers = self.kernel_timers.lock();
+            let timer = match timers.get_mut(&id) {
+                Some(timer) if timer.seq == seq && timer.armed.is_some() => timer,
+                // cancelled or modified after the event expired
+                _ => return,
+            };
+            timer.armed = None;
+            match timer.callback.take() {
+                Some(callback) => callback,
+                // still running on another CPU, after being migrated
+                None => return,
+            }
+        };
+        callback();
+        let mut timers = self.kernel_timers.lock();
+        let timer = match timers.get_mut(&id) {
+            Some(timer) => timer,
+            // cancelled while running
+            None => return,
+        };
+        timer.callback = Some(callback);
+        if timer.armed.is_some() {
+            // modified while running
+            return;
+        }
+        let removed = match timer.period {
+            Some(period) => {
+                let now = self.ticks();
+                let mut deadline = timer.deadline.wrapping_add(period);
+                if now.wrapping_sub(deadline) < usize::max_value() / 2 {
+                    // skip missed periods
+                    deadline = now.wrapping_add(period);
+                }
+                self.arm_timer(id, timer, deadline);
+                None
+            }
+            None => timers.remove(&id),
+        };
+        drop(timers);
+        drop(removed);
+    }
+
+    /// Set or clear the real-time reservation of thread `tid`,
+    /// if the scheduler supports it, e.g. `EdfScheduler`.
+    ///
+    /// It's released when the thread exits.
+    pub fn set_reservation(
+        &self,
+        tid: Tid,
+        reservation: Option<Reservation>,
+    ) -> Result<(), ReserveError> {
+        // keep the slot locked, so it's not released before the thread exits
+        let proc_lock = self.lock(tid).ok_or(ReserveError::NoThread)?;
+        // it was released on exit already
+        if let Status::Exited(_) = proc_lock.as_ref().unwrap().status {
+            return Err(ReserveError::NoThread);
+        }
+        self.scheduler.reserve(tid_index(tid), reservation)
+    }
+
+    /// Number of deadlines the reservation of thread `tid` missed.
+    pub fn deadline_misses(&self, tid: Tid) -> usize {
+        match self.lock(tid) {
+            Some(_) => self.scheduler.deadline_misses(tid_index(tid)),
+            None => 0,
         }
     }
 
     /// Set the priority of thread `tid`
+    ///
+    /// This is the base priority. While `tid` inherits a higher priority
+    /// from a PI lock, the scheduler keeps seeing the inherited one.
     pub fn set_priority(&self, tid: Tid, priority: u8) {
-        self.scheduler.set_priority(tid, priority);
-        let mut proc_lock = self.threads[tid].lock();
-        proc_lock.as_mut().unwrap().priority = priority;
+        if let Some(mut proc_lock) = self.lock(tid) {
+            let proc = proc_lock.as_mut().unwrap();
+            proc.priority = priority;
+            if !self.update_effective_priority(proc) {
+                self.scheduler
+                    .update_priority(tid_index(tid), proc.effective_priority);
+            }
+        }
     }
 
     /// Get thread[tid].pri
-    pub fn get_pri(&self, tid: Tid) -> u8 {
-        self.threads[tid].lock().as_ref().unwrap().priority
+    ///
+    /// This is the effective priority, i.e. the one the scheduler uses,
+    /// which may be inherited from a higher-priority thread blocked on a PI lock.
+    /// Use `get_base_pri` for the priority set by `set_priority`.
+    /// Return `None` if `tid` is stale.
+    pub fn get_pri(&self, tid: Tid) -> Option<u8> {
+        self.lock(tid)
+            .map(|proc_lock| proc_lock.as_ref().unwrap().effective_priority)
+    }
+
+    /// Get the name of thread `tid`.
+    /// Return `None` if it has no name, or `tid` is stale.
+    pub fn name(&self, tid: Tid) -> Option<String> {
+        self.lock(tid)?.as_ref().unwrap().name.clone()
+    }
+
+    /// Get the base priority of thread `tid`, ignoring priority inheritance.
+    /// Return `None` if `tid` is stale.
+    pub fn get_base_pri(&self, tid: Tid) -> Option<u8> {
+        self.lock(tid).map(|proc_lock| proc_lock.as_ref().unwrap().priority)
+    }
+
+    /// Recompute the effective priority of `proc` and tell the scheduler.
+    /// Return whether it changed.
+    fn update_effective_priority(&self, proc: &mut Thread) -> bool {
+        let priority = proc
+            .inherited
+            .iter()
+            .map(|&(_, priority)| priority)
+            .fold(proc.priority, u8::max);
+        if priority == proc.effective_priority {
+            return false;
+        }
+        trace!(
+            "thread {} priority {} -> {}",
+            proc,
+            proc.effective_priority,
+            priority
+        );
+        proc.effective_priority = priority;
+        self.scheduler.update_priority(tid_index(proc.tid), priority);
+        true
+    }
+
+    /// Called by a PI lock when thread `tid` is going to block on it,
+    /// or the lock `tid` blocked on changed hands.
+    ///
+    /// `tid` lends its effective priority to `owner` of the lock.
+    /// If `owner` is blocked on another PI lock, the boost goes on along the chain.
+    pub(crate) fn pi_block(&self, tid: Tid, lock: usize, owner: Tid) {
+        let mut priority = match self.lock(tid) {
+            Some(mut proc_lock) => {
+                let proc = proc_lock.as_mut().unwrap();
+                proc.blocked_on = Some((lock, owner));
+                proc.effective_priority
+            }
+            None => return,
+        };
+        self.scheduler.lend(tid_index(tid), tid_index(owner));
+        let (mut lock, mut owner) = (lock, owner);
+        // Stops when a priority doesn't change, so a cycle (deadlock) won't loop forever.
+        while let Some(mut proc_lock) = self.lock(owner) {
+            let proc = proc_lock.as_mut().unwrap();
+            match proc.inherited.iter_mut().find(|(id, _)| *id == lock) {
+                Some((_, inherited)) => *inherited = priority.max(*inherited),
+                None => proc.inherited.push((lock, priority)),
+            }
+            if !self.update_effective_priority(proc) {
+                return;
+            }
+            priority = proc.effective_priority;
+            match proc.blocked_on {
+                Some((next_lock, next_owner)) => {
+                    lock = next_lock;
+                    owner = next_owner;
+                }
+                None => return,
+            }
+        }
     }
-    
+
+    /// Called by a PI lock when thread `tid` releases it.
+    /// Drop the priority inherited through it.
+    pub(crate) fn pi_release(&self, tid: Tid, lock: usize) {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            let proc = proc_lock.as_mut().unwrap();
+            proc.inherited.retain(|&(id, _)| id != lock);
+            self.update_effective_priority(proc);
+        }
+    }
+
+    /// Called by a PI lock when it's handed over to thread `tid` blocked on it.
+    ///
+    /// The lock should then call `pi_block` for the rest waiters,
+    /// to let them lend their priority to the new owner.
+    pub(crate) fn pi_acquire(&self, tid: Tid) {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            proc_lock.as_mut().unwrap().blocked_on = None;
+        }
+    }
+
+    /// Start counting the ticks of thread `tid` in the scheduler.
+    /// Do nothing if `tid` is stale.
     pub fn start(&self, tid: Tid) {
-        self.scheduler.start(tid);
+        if let Some(_proc_lock) = self.lock(tid) {
+            self.scheduler.start(tid_index(tid));
+        }
     }
 
-    pub fn get_tick(&self, tid: Tid) -> u8 {
-        self.scheduler.get_tick(tid)
+    /// Return `None` if `tid` is stale.
+    pub fn get_tick(&self, tid: Tid) -> Option<u8> {
+        let _proc_lock = self.lock(tid)?;
+        Some(self.scheduler.get_tick(tid_index(tid)))
     }
 
+    /// Do nothing if `tid` is stale.
     pub fn end(&self, tid: Tid) {
-        self.scheduler.end(tid);
+        if let Some(_proc_lock) = self.lock(tid) {
+            self.scheduler.end(tid_index(tid));
+        }
     }
 
+    /// Do nothing if `tid` is stale.
     pub fn set_success(&self, tid: Tid, value: bool) {
-        self.scheduler.set_success(tid, value);
+        if let Some(_proc_lock) = self.lock(tid) {
+            self.scheduler.set_success(tid_index(tid), value);
+        }
     }
 
-    pub fn get_success(&self, tid: Tid) -> bool {
-        self.scheduler.get_success(tid)
+    /// Return `None` if `tid` is stale.
+    pub fn get_success(&self, tid: Tid) -> Option<bool> {
+        let _proc_lock = self.lock(tid)?;
+        Some(self.scheduler.get_success(tid_index(tid)))
     }
 
+    /// Do nothing if `tid` is stale.
     pub fn reset_slice(&self, tid: Tid) {
-        self.scheduler.reset_slice(tid)
+        if let Some(_proc_lock) = self.lock(tid) {
+            self.scheduler.reset_slice(tid_index(tid));
+        }
     }
 
     /// Called by Processor to get a thread to run.
     /// The manager first mark it `Running`,
     /// then take out and return its Context.
+    ///
+    /// Only threads allowed to run on `cpu_id` are selected.
     pub(crate) fn run(&self, cpu_id: usize) -> Option<(Tid, Box<dyn Context>)> {
         // info!("in thread_pool run");
-        self.scheduler.pop(cpu_id).map(|tid| {
-            let mut proc_lock = self.threads[tid].lock();
-            let mut proc = proc_lock.as_mut().expect("thread not exist");
-            proc.status = Status::Running(cpu_id);
-            (tid, proc.context.take().expect("context not exist"))
-        })
+        self.scheduler.set_time(self.ticks());
+        let index = {
+            let pinned = self.pinned.lock();
+            if pinned.is_empty() {
+                self.scheduler.pop(cpu_id)
+            } else {
+                self.scheduler.pop_allowed(cpu_id, &|index| {
+                    pinned
+                        .get(&index)
+                        .map_or(true, |&affinity| runs_on(affinity, cpu_id))
+                })
+            }
+        }?;
+        let mut proc_lock = self.threads.get(index).expect("thread not exist").lock();
+        let proc = proc_lock.as_mut().expect("thread not exist");
+        trace!("thread {} running on CPU{}", proc, cpu_id);
+        proc.account(self.ticks());
+        self.charged[cpu_id % MAX_CPUS].store(self.ticks(), Ordering::Relaxed);
+        proc.status = Status::Running(cpu_id);
+        proc.cpu = cpu_id;
+        proc.stats.last_cpu = Some(cpu_id);
+        Some((proc.tid, proc.context.take().expect("context not exist")))
     }
 
     /// Called by Processor to finish running a thread
     /// and give its context back.
     pub(crate) fn stop(&self, tid: Tid, context: Box<dyn Context>) {
-        let mut proc_lock = self.threads[tid].lock();
-        let proc = proc_lock.as_mut().expect("thread not exist");
+        let mut proc_lock = self.lock(tid).expect("thread not exist");
+        let proc = proc_lock.as_mut().unwrap();
+        proc.account(self.ticks());
+        match proc.status_after_stop {
+            Status::Ready => proc.stats.involuntary_switches += 1,
+            Status::Sleeping => proc.stats.voluntary_switches += 1,
+            _ => {}
+        }
         proc.status = proc.status_after_stop.clone();
         proc.status_after_stop = Status::Ready;
         proc.context = Some(context);
         match proc.status {
-            Status::Ready => self.scheduler.push(tid),
-            Status::Exited(_) => self.exit_handler(proc_lock),
+            Status::Ready => self.scheduler.push(tid_index(tid)),
+            Status::Exited(_) => self.exit_handler(tid, proc_lock),
             _ => {}
         }
     }
 
-    /// Called by `JoinHandle` to let thread `tid` wait for `target`.
+    /// Let thread `tid` wait for `target`.
     /// The `tid` is going to sleep, and will be woke up when `target` exit.
     /// (see `exit_handler()`)
-    pub(crate) fn wait(&self, tid: Tid, target: Tid) {
+    ///
+    /// Any number of threads can wait for the same `target`.
+    /// If `target` has already exited or been removed, `tid` won't sleep.
+    /// The caller should yield afterwards, then check `target` again.
+    pub fn wait(&self, tid: Tid, target: Tid) {
+        // Go to sleep first, so we never hold two thread locks at once.
         self.set_status(tid, Status::Sleeping);
-        let mut target_lock = self.threads[target].lock();
-        let target = target_lock.as_mut().expect("thread not exist");
-        target.waiter = Some(tid);
+        if let Some(target_lock) = self.lock(target) {
+            let target = target_lock.as_ref().unwrap();
+            match target.status {
+                Status::Exited(_) => {}
+                _ => {
+                    target.joiners.push(tid);
+                    self.scheduler.lend(tid_index(tid), tid_index(target.tid));
+                    return;
+                }
+            }
+        }
+        // nothing to wait for
+        self.wakeup(tid);
+    }
+
+    /// Get the status of thread `tid`.
+    /// Return `None` if `tid` is stale.
+    pub fn status(&self, tid: Tid) -> Option<Status> {
+        self.lock(tid)
+            .map(|proc_lock| proc_lock.as_ref().unwrap().status.clone())
+    }
+
+    /// Get the CPU accounting of thread `tid`, including its current status so far.
+    /// Return `None` if `tid` is stale.
+    ///
+    /// The accounting stops when the thread exits.
+    pub fn thread_stats(&self, tid: Tid) -> Option<ThreadStats> {
+        let mut proc_lock = self.lock(tid)?;
+        let proc = proc_lock.as_mut().unwrap();
+        proc.account(self.ticks());
+        Some(proc.stats)
+    }
+
+    /// List all threads in the pool, in the order of their slots.
+    ///
+    /// Each thread is seen consistently under the lock of its slot,
+    /// but not all of them at the same instant. Print it with `ThreadList`.
+    pub fn snapshot(&self) -> Vec<ThreadInfo> {
+        let now = self.ticks();
+        let mut infos = Vec::new();
+        for slot in self.threads.iter() {
+            let mut proc_lock = slot.lock();
+            let proc = match proc_lock.as_mut() {
+                Some(proc) => proc,
+                None => continue,
+            };
+            proc.account(now);
+            let wait_reason = match proc.status {
+                Status::Sleeping => Some(match proc.blocked_on {
+                    Some((lock, owner)) => WaitReason::PiLock { lock, owner },
+                    None if proc.parked => WaitReason::Park,
+                    None => WaitReason::Other,
+                }),
+                _ => None,
+            };
+            infos.push(ThreadInfo {
+                tid: proc.tid,
+                name: proc.name.clone(),
+                status: proc.status.clone(),
+                priority: proc.priority,
+                effective_priority: proc.effective_priority,
+                affinity: proc.affinity,
+                detached: proc.detached,
+                killed: proc.killed && proc.abort.is_none(),
+                wait_reason,
+                joiners: proc.joiners.waiters(),
+                stats: proc.stats,
+            });
+        }
+        // tell the joiners from the waiters of other queues
+        let indexes: BTreeMap<Tid, usize> = infos
+            .iter()
+            .enumerate()
+            .map(|(i, info)| (info.tid, i))
+            .collect();
+        for target in 0..infos.len() {
+            for joiner in infos[target].joiners.clone() {
+                let tid = infos[target].tid;
+                if let Some(&i) = indexes.get(&joiner) {
+                    if infos[i].wait_reason == Some(WaitReason::Other) {
+                        infos[i].wait_reason = Some(WaitReason::Join(tid));
+                    }
+                }
+            }
+        }
+        infos
     }
 
     /// Switch the status of a thread.
     /// Insert/Remove it to/from scheduler if necessary.
+    /// Do nothing if `tid` is stale.
     fn set_status(&self, tid: Tid, status: Status) {
-        let mut proc_lock = self.threads[tid].lock();
-        if let Some(mut proc) = proc_lock.as_mut() {
-            trace!("thread {} {:?} -> {:?}", tid, proc.status, status);
+        if let Some(proc_lock) = self.lock(tid) {
+            self.set_status_locked(tid, proc_lock, status);
+        }
+    }
+
+    /// `set_status` with the slot of `tid` locked.
+    fn set_status_locked(
+        &self,
+        tid: Tid,
+        mut proc_lock: MutexGuard<'_, Option<Thread>>,
+        status: Status,
+    ) {
+        if let Some(proc) = proc_lock.as_mut() {
+            trace!("thread {} {:?} -> {:?}", proc, proc.status, status);
             match (&proc.status, &status) {
                 (Status::Ready, Status::Ready) => return,
-                (Status::Ready, _) => self.scheduler.remove(tid),
+                (Status::Ready, _) => self.scheduler.remove(tid_index(tid)),
                 (Status::Exited(_), _) => panic!("can not set status for a exited thread"),
-                (Status::Sleeping, Status::Exited(_)) => self.timer.lock().stop(Event::Wakeup(tid)),
+                (Status::Sleeping, Status::Exited(_)) => self.stop_wakeup_timer(proc),
                 (Status::Running(_), Status::Ready) => {} // thread will be added to scheduler in stop()
-                (_, Status::Ready) => self.scheduler.push(tid),
+                (_, Status::Ready) => self.scheduler.push(tid_index(tid)),
                 _ => {}
             }
             match proc.status {
                 Status::Running(_) => proc.status_after_stop = status,
-                _ => proc.status = status,
+                _ => {
+                    proc.account(self.ticks());
+                    proc.status = status;
+                }
             }
             match proc.status {
-                Status::Exited(_) => self.exit_handler(proc_lock),
+                Status::Exited(_) => self.exit_handler(tid, proc_lock),
                 _ => {}
             }
         }
     }
 
+    /// Release all resources of thread `tid` on exit.
+    /// If it has already exited, release them now.
+    /// Do nothing if `tid` is stale.
     pub fn detach(&self, tid: Tid) {
-        let mut proc_lock = self.threads[tid].lock();
-        let proc = proc_lock.as_mut().expect("thread not exist");
-        assert!(!proc.detached);
-        proc.detached = true;
+        if let Some(mut proc_lock) = self.lock(tid) {
+            let proc = proc_lock.as_mut().unwrap();
+            assert!(!proc.detached);
+            proc.detached = true;
+            if let Status::Exited(_) = proc.status {
+                *proc_lock = None;
+                self.threads.free(tid);
+            }
+        }
     }
 
     /// Try to remove an exited thread `tid`.
-    /// Return its exit code if success.
+    /// Return its exit code if success. The exit code of an aborted thread is 0.
+    /// Return `None` if it is still alive or `tid` is stale.
     pub fn try_remove(&self, tid: Tid) -> Option<ExitCode> {
-        let mut proc_lock = self.threads[tid].lock();
-        let proc = proc_lock.as_ref().expect("thread not exist");
+        self.try_join(tid).map(|ret| ret.unwrap_or(0))
+    }
+
+    /// Try to remove an exited thread `tid`.
+    /// Return `Ok(exit code)` if it exited normally, or `Err` if it panicked or was killed.
+    /// Return `None` if it is still alive or `tid` is stale.
+    pub fn try_join(&self, tid: Tid) -> Option<Result<ExitCode, Abort>> {
+        let mut proc_lock = self.lock(tid)?;
+        let proc = proc_lock.as_mut().unwrap();
         match proc.status {
             Status::Exited(code) => {
+                let ret = match proc.abort.take() {
+                    Some(abort) => Err(abort),
+                    None => Ok(code),
+                };
                 // release the tid
                 *proc_lock = None;
-                Some(code)
+                self.threads.free(tid);
+                Some(ret)
             }
             _ => None,
         }
@@ -254,55 +1296,612 @@ impl ThreadPool {
     /// Sleep `tid` for `time` ticks.
     /// `time` == 0 means sleep forever
     pub fn sleep(&self, tid: Tid, time: usize) {
-        self.set_status(tid, Status::Sleeping);
+        let mut proc_lock = match self.lock(tid) {
+            Some(proc_lock) => proc_lock,
+            None => return,
+        };
+        // start the timer with the slot locked, so a `wakeup` can always cancel it
+        self.start_wakeup_timer(tid, proc_lock.as_mut().unwrap(), time);
+        self.set_status_locked(tid, proc_lock, Status::Sleeping);
+    }
+
+    /// Wake up thread `tid` after `time` ticks, replacing the previous timer.
+    /// `time` == 0 means never.
+    ///
+    /// The timer is armed on the CPU it runs or last ran on.
+    fn start_wakeup_timer(&self, tid: Tid, proc: &mut Thread, time: usize) {
+        self.stop_wakeup_timer(proc);
         if time != 0 {
-            self.timer.lock().start(time, Event::Wakeup(tid));
+            let cpu_id = proc.cpu;
+            // the queue may lag behind the clock
+            let time = self.ticks().wrapping_add(time.min(usize::max_value() / 2));
+            let handle = self.timer(cpu_id).lock().start_at(time, Event::Wakeup(tid));
+            proc.wakeup_timer = Some((cpu_id, handle));
+        }
+    }
+
+    /// Cancel the timer to wake up `proc`, if any.
+    fn stop_wakeup_timer(&self, proc: &mut Thread) {
+        if let Some((cpu_id, handle)) = proc.wakeup_timer.take() {
+            self.timer(cpu_id).lock().cancel(handle);
         }
     }
 
     /// Cancel sleeping after stop
     pub fn cancel_sleeping(&self, tid: Tid) {
-        let mut proc_lock = self.threads[tid].lock();
-        if let Some(mut proc) = proc_lock.as_mut() {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            let proc = proc_lock.as_mut().unwrap();
             if let Status::Sleeping = proc.status_after_stop {
                 proc.status_after_stop = Status::Ready;
             }
         }
     }
 
-    pub fn wakeup(&self, tid: Tid) {
-        let mut proc_lock = self.threads[tid].lock();
-        if let Some(mut proc) = proc_lock.as_mut() {
-            trace!("thread {} {:?} -> {:?}", tid, proc.status, Status::Ready);
-            if let Status::Sleeping = proc.status {
+    /// Wake up a sleeping thread `tid`.
+    ///
+    /// If `tid` is still running but going to sleep after stop,
+    /// it will be ready again after stop instead.
+    /// The timer of its sleeping, if any, is cancelled.
+    ///
+    /// Return whether `tid` was sleeping (or going to).
+    /// Do nothing if `tid` is stale.
+    pub fn wakeup(&self, tid: Tid) -> bool {
+        match self.lock(tid) {
+            Some(mut proc_lock) => self.wakeup_locked(tid, proc_lock.as_mut().unwrap()),
+            None => false,
+        }
+    }
+
+    /// `wakeup` with the slot of `tid` locked.
+    fn wakeup_locked(&self, tid: Tid, proc: &mut Thread) -> bool {
+        trace!("thread {} {:?} -> {:?}", proc, proc.status, Status::Ready);
+        match (&proc.status, &proc.status_after_stop) {
+            (Status::Sleeping, _) => {
+                proc.account(self.ticks());
                 proc.status = Status::Ready;
-                self.scheduler.push(tid);
+                self.scheduler.push(tid_index(tid));
+            }
+            (Status::Running(_), Status::Sleeping) => {
+                proc.status_after_stop = Status::Ready;
             }
+            _ => return false,
         }
+        self.stop_wakeup_timer(proc);
+        true
     }
 
+    /// Park thread `tid` like `std::thread::park`.
+    ///
+    /// If its park token is available, consume it and return `false`.
+    /// Otherwise put it to sleep until `unpark`, or for `time` ticks if `time` != 0,
+    /// and return `true`. Then the thread should yield, and call `end_park` on wake up.
+    ///
+    /// Return `false` if `tid` is stale.
+    pub fn park(&self, tid: Tid, time: usize) -> bool {
+        let mut proc_lock = match self.lock(tid) {
+            Some(proc_lock) => proc_lock,
+            None => return false,
+        };
+        let proc = proc_lock.as_mut().unwrap();
+        if proc.park_token {
+            proc.park_token = false;
+            return false;
+        }
+        proc.parked = true;
+        // start the timer with the slot locked, so an `unpark` can always cancel it
+        self.start_wakeup_timer(tid, proc, time);
+        self.set_status_locked(tid, proc_lock, Status::Sleeping);
+        true
+    }
+
+    /// Called by a thread parked by `park` after it wakes up.
+    ///
+    /// Return whether it was unparked, consuming the token.
+    /// Otherwise it timed out, or was woken up by others (e.g. `kill`).
+    pub fn end_park(&self, tid: Tid) -> bool {
+        match self.lock(tid) {
+            Some(mut proc_lock) => {
+                let proc = proc_lock.as_mut().unwrap();
+                proc.parked = false;
+                core::mem::replace(&mut proc.park_token, false)
+            }
+            None => false,
+        }
+    }
+
+    /// Make the park token of thread `tid` available,
+    /// and wake it up if it's parked. See `park`.
+    /// Do nothing if `tid` is stale.
+    pub fn unpark(&self, tid: Tid) {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            let proc = proc_lock.as_mut().unwrap();
+            proc.park_token = true;
+            if proc.parked {
+                self.wakeup_locked(tid, proc);
+            }
+        }
+    }
+
+    /// Exit thread `tid` with `code` right away.
+    /// To stop another thread safely, use `kill` instead.
+    /// Do nothing if `tid` is stale.
     pub fn exit(&self, tid: Tid, code: ExitCode) {
         // NOTE: if `tid` is running, status change will be deferred.
         self.set_status(tid, Status::Exited(code));
     }
+
+    /// Exit `tid` because it panicked with `message`.
+    /// The message is handed to its joiner by `try_join`.
+    ///
+    /// Return `false` if `tid` is stale, exited, or already panicking,
+    /// so a panic while handling a panic is not handled again.
+    /// Also return `false` if `tid` is in a `std_thread::scope`,
+    /// or it's locked now, as the panic may have happened with the lock held.
+    pub fn exit_on_panic(&self, tid: Tid, message: String) -> bool {
+        {
+            let mut proc_lock = match self.try_lock(tid) {
+                Some(lock) => lock,
+                None => return false,
+            };
+            let proc = proc_lock.as_mut().unwrap();
+            if let Status::Exited(_) = proc.status {
+                return false;
+            }
+            if proc.scopes != 0 {
+                return false;
+            }
+            if let Some(Abort::Panicked(_)) = proc.abort {
+                return false;
+            }
+            warn!("thread {} panicked: {}", proc, message);
+            proc.abort = Some(Abort::Panicked(message));
+        }
+        self.exit(tid, 0);
+        true
+    }
+
+    /// Cancel thread `tid`.
+    ///
+    /// It's cooperative: the thread exits at its next cancellation point,
+    /// which is whenever it blocks or yields, or when it starts running.
+    /// A sleeping or waiting thread is woken up to meet it.
+    /// A running or preempted thread goes on until it reaches one,
+    /// as it's never cancelled at an arbitrary point.
+    /// Sections guarded by `disable_cancel`, such as holding a lock of `sync`,
+    /// are never cut off: the cancellation waits until the thread leaves them.
+    ///
+    /// Its joiner gets `Abort::Killed` from `try_join`.
+    /// The stack of the thread is not unwound: the destructors on it don't run.
+    ///
+    /// Return `false` if `tid` is stale or exited.
+    pub fn kill(&self, tid: Tid) -> bool {
+        {
+            let mut proc_lock = match self.lock(tid) {
+                Some(lock) => lock,
+                None => return false,
+            };
+            let proc = proc_lock.as_mut().unwrap();
+            if let Status::Exited(_) = proc.status {
+                return false;
+            }
+            trace!("thread {} killed", proc);
+            proc.killed = true;
+        }
+        self.wakeup(tid);
+        true
+    }
+
+    /// Whether thread `tid` is killed and going to exit.
+    /// Return `false` if `tid` is stale.
+    pub fn is_killed(&self, tid: Tid) -> bool {
+        self.lock(tid)
+            .map_or(false, |proc_lock| proc_lock.as_ref().unwrap().killed)
+    }
+
+    /// Defer the cancellation of `tid` until `enable_cancel`. Can be nested.
+    /// Do nothing if `tid` is stale.
+    pub fn disable_cancel(&self, tid: Tid) {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            proc_lock.as_mut().unwrap().no_cancel += 1;
+        }
+    }
+
+    /// Leave a section entered by `disable_cancel`.
+    /// Do nothing if `tid` is stale.
+    pub fn enable_cancel(&self, tid: Tid) {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            let proc = proc_lock.as_mut().unwrap();
+            proc.no_cancel = proc.no_cancel.saturating_sub(1);
+        }
+    }
+
+    /// Called when thread `tid` enters a `std_thread::scope`. Can be nested.
+    /// Do nothing if `tid` is stale.
+    pub(crate) fn enter_scope(&self, tid: Tid) {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            proc_lock.as_mut().unwrap().scopes += 1;
+        }
+    }
+
+    /// Called when thread `tid` leaves a `std_thread::scope`.
+    /// Do nothing if `tid` is stale.
+    pub(crate) fn leave_scope(&self, tid: Tid) {
+        if let Some(mut proc_lock) = self.lock(tid) {
+            let proc = proc_lock.as_mut().unwrap();
+            proc.scopes = proc.scopes.saturating_sub(1);
+        }
+    }
+
+    /// Whether thread `tid` is killed and will exit at its next cancellation point,
+    /// i.e. not deferred by `disable_cancel`.
+    /// Blocking primitives use it to undo their bookkeeping before they yield.
+    pub(crate) fn cancel_pending(&self, tid: Tid) -> bool {
+        match self.lock(tid) {
+            Some(proc_lock) => {
+                let proc = proc_lock.as_ref().unwrap();
+                proc.killed && proc.no_cancel == 0 && proc.abort.is_none()
+            }
+            None => false,
+        }
+    }
+
+    /// Called by Processor at a cancellation point of thread `tid`.
+    /// Exit it if it's killed and not in a section deferring the cancellation.
+    /// Return whether it's exiting.
+    pub(crate) fn test_cancel(&self, tid: Tid) -> bool {
+        {
+            let mut proc_lock = match self.lock(tid) {
+                Some(lock) => lock,
+                None => return false,
+            };
+            let proc = proc_lock.as_mut().unwrap();
+            if !proc.killed || proc.no_cancel != 0 || proc.abort.is_some() {
+                return false;
+            }
+            // already returned
+            if let Status::Exited(_) = proc.status_after_stop {
+                return false;
+            }
+            proc.abort = Some(Abort::Killed);
+        }
+        self.exit(tid, 0);
+        true
+    }
+    /// Get the thread local value of `tid` keyed by `key`.
+    /// Return `None` if it's not initialized, or `tid` is stale.
+    ///
+    /// The value lives until the thread exits.
+    pub(crate) fn local(&self, tid: Tid, key: usize) -> Option<*const dyn Any> {
+        let proc_lock = self.lock(tid)?;
+        let proc = proc_lock.as_ref().unwrap();
+        proc.locals.get(&key).map(|value| &**value as *const dyn Any)
+    }
+
+    /// Set the thread local value of `tid` keyed by `key`, if not set yet.
+    /// Return the value in the thread, or `None` if `tid` is stale.
+    pub(crate) fn init_local(
+        &self,
+        tid: Tid,
+        key: usize,
+        value: Box<dyn Any>,
+    ) -> Option<*const dyn Any> {
+        let mut proc_lock = self.lock(tid)?;
+        let proc = proc_lock.as_mut().unwrap();
+        if let Status::Exited(_) = proc.status {
+            // don't let values outlive their destructors
+            return None;
+        }
+        let value = proc.locals.entry(key).or_insert(value);
+        Some(&**value as *const dyn Any)
+    }
+
     /// Called when a thread exit
-    fn exit_handler(&self, mut proc_lock: MutexGuard<'_, Option<Thread>>) {
+    ///
+    /// The destructors of its thread locals run here, after the thread is unlocked.
+    /// They can't create new thread locals, as the thread has exited.
+    fn exit_handler(&self, tid: Tid, mut proc_lock: MutexGuard<'_, Option<Thread>>) {
         let proc = proc_lock.as_mut().expect("thread not exist");
-        // wake up waiter
-        if let Some(waiter) = proc.waiter {
-            self.wakeup(waiter);
-        }
+        // run destructors of thread locals at last, when no lock is held
+        let locals = mem::take(&mut proc.locals);
+        // wake up waiters
+        proc.joiners.notify_all(self);
         // drop its context
         proc.context = None;
+        // release its reservation
+        self.scheduler.reserve(tid_index(tid), None).ok();
         // release all if detached
         if proc.detached {
             *proc_lock = None;
+            self.threads.free(tid);
         }
+        drop(proc_lock);
+        drop(locals);
     }
 }
 
-fn new_vec_default<T: Default>(size: usize) -> Vec<T> {
-    let mut vec = Vec::new();
-    vec.resize_with(size, Default::default);
-    vec
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+    use crate::scheduler::{EdfScheduler, RRScheduler};
+    use alloc::sync::Arc;
+
+    fn counter() -> (Arc<AtomicUsize>, impl FnMut() + Send + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count1 = count.clone();
+        (count, move || {
+            count1.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    fn run_ticks(pool: &ThreadPool, cpu_id: usize, ticks: usize) {
+        for _ in 0..ticks {
+            pool.tick(cpu_id, None);
+        }
+    }
+
+    struct NoContext;
+
+    impl Context for NoContext {
+        unsafe fn switch_to(&mut self, _target: &mut dyn Context) {}
+    }
+
+    #[test]
+    fn thread_stats() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let tid = pool.add(Box::new(NoContext));
+        run_ticks(&pool, 0, 2);
+        let (tid1, context) = pool.run(1).unwrap();
+        assert_eq!(tid1, tid);
+        for _ in 0..3 {
+            pool.tick(1, Some(tid));
+        }
+        pool.sleep(tid, 0);
+        pool.stop(tid, context);
+        run_ticks(&pool, 1, 4);
+        pool.wakeup(tid);
+        run_ticks(&pool, 1, 1);
+        let stats = pool.thread_stats(tid).unwrap();
+        assert_eq!(stats.ready_ticks, 3);
+        assert_eq!(stats.run_ticks, 3);
+        assert_eq!(stats.sleep_ticks, 4);
+        assert_eq!(stats.voluntary_switches, 1);
+        assert_eq!(stats.involuntary_switches, 0);
+        assert_eq!(stats.last_cpu, Some(1));
+    }
+
+    #[test]
+    fn snapshot() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let attr = ThreadAttr {
+            name: Some(String::from("worker")),
+            ..ThreadAttr::default()
+        };
+        let target = pool.try_add_with(Box::new(NoContext), attr).unwrap();
+        let joiner = pool.add(Box::new(NoContext));
+        pool.wait(joiner, target);
+        let parked = pool.add(Box::new(NoContext));
+        assert!(pool.park(parked, 0));
+        let infos = pool.snapshot();
+        assert_eq!(infos.len(), 3);
+        assert_eq!(infos[0].name.as_ref().map(|s| s.as_str()), Some("worker"));
+        assert_eq!(infos[0].status, Status::Ready);
+        assert_eq!(infos[0].joiners, [joiner]);
+        assert_eq!(infos[1].wait_reason, Some(WaitReason::Join(target)));
+        assert_eq!(infos[2].wait_reason, Some(WaitReason::Park));
+        let table = format!("{}", ThreadList(&infos));
+        assert_eq!(table.lines().count(), 4);
+        assert!(table.contains("worker"));
+        assert!(table.contains(&format!("join {}", target)));
+    }
+
+    #[test]
+    fn hard_cap() {
+        // across the segments of the table, growing on demand
+        let pool = ThreadPool::new(RRScheduler::new(5), 40);
+        let first = pool.add(Box::new(NoContext));
+        for _ in 1..40 {
+            pool.add(Box::new(NoContext));
+        }
+        assert_eq!(
+            pool.try_add(Box::new(NoContext)).err(),
+            Some(ThreadError::Exhausted)
+        );
+        pool.exit(first, 3);
+        // still taken until joined
+        assert!(pool.try_add(Box::new(NoContext)).is_err());
+        assert_eq!(pool.try_join(first), Some(Ok(3)));
+        let tid = pool.try_add(Box::new(NoContext)).unwrap();
+        assert_eq!(tid_index(tid), tid_index(first));
+        assert_ne!(tid, first);
+    }
+
+    #[test]
+    fn all_joiners_woken() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let target = pool.add(Box::new(NoContext));
+        let joiners: Vec<Tid> = (0..3).map(|_| pool.add(Box::new(NoContext))).collect();
+        for &joiner in joiners.iter() {
+            pool.wait(joiner, target);
+            assert_eq!(pool.status(joiner), Some(Status::Sleeping));
+        }
+        pool.exit(target, 5);
+        for &joiner in joiners.iter() {
+            assert_eq!(pool.status(joiner), Some(Status::Ready));
+        }
+        // one of them gets the exit code, the others find it gone
+        assert_eq!(pool.try_join(target), Some(Ok(5)));
+        assert_eq!(pool.try_join(target), None);
+        // a late joiner doesn't sleep
+        let late = pool.add(Box::new(NoContext));
+        pool.wait(late, target);
+        assert_eq!(pool.status(late), Some(Status::Ready));
+    }
+
+    #[test]
+    fn stale_tid() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let stale = pool.add(Box::new(NoContext));
+        pool.exit(stale, 0);
+        assert_eq!(pool.try_remove(stale), Some(0));
+        let tid = pool.add(Box::new(NoContext));
+        assert_eq!(tid_index(tid), tid_index(stale));
+        pool.set_success(stale, true);
+        pool.start(stale);
+        assert_eq!(pool.get_success(stale), None);
+        assert_eq!(pool.get_tick(stale), None);
+        assert_eq!(pool.get_success(tid), Some(false));
+        assert_eq!(pool.get_tick(tid), Some(0));
+    }
+
+    #[test]
+    fn affinity() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let attr = ThreadAttr {
+            affinity: 0b10,
+            ..ThreadAttr::default()
+        };
+        let pinned = pool.try_add_with(Box::new(NoContext), attr).unwrap();
+        let free = pool.add(Box::new(NoContext));
+        // CPU0 skips the pinned thread, which keeps its place for CPU1
+        let (tid, _context) = pool.run(0).unwrap();
+        assert_eq!(tid, free);
+        assert!(pool.run(0).is_none());
+        let (tid, _context) = pool.run(1).unwrap();
+        assert_eq!(tid, pinned);
+    }
+
+    #[test]
+    fn reserve_exited() {
+        let pool = ThreadPool::unbounded(EdfScheduler::new(1, 5));
+        let tid = pool.add(Box::new(NoContext));
+        let reservation = Reservation {
+            runtime: 5,
+            deadline: 10,
+            period: 10,
+        };
+        assert_eq!(pool.set_reservation(tid, Some(reservation)), Ok(()));
+        pool.exit(tid, 0);
+        // not joined yet, but it would never be released again
+        assert_eq!(
+            pool.set_reservation(tid, Some(reservation)),
+            Err(ReserveError::NoThread)
+        );
+        let other = pool.add(Box::new(NoContext));
+        assert_eq!(pool.set_reservation(other, Some(reservation)), Ok(()));
+    }
+
+    #[test]
+    fn exit_on_panic() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let tid = pool.add(Box::new(NoContext));
+        // may have panicked while holding its lock
+        let proc_lock = pool.lock(tid);
+        assert!(!pool.exit_on_panic(tid, String::from("locked")));
+        drop(proc_lock);
+        pool.enter_scope(tid);
+        assert!(!pool.exit_on_panic(tid, String::from("in scope")));
+        pool.leave_scope(tid);
+        assert!(pool.exit_on_panic(tid, String::from("boom")));
+        assert_eq!(
+            pool.try_join(tid),
+            Some(Err(Abort::Panicked(String::from("boom"))))
+        );
+    }
+
+    #[test]
+    fn locals_dropped_unlocked() {
+        struct Probe(Arc<ThreadPool>, Tid, Arc<AtomicUsize>);
+        impl Drop for Probe {
+            fn drop(&mut self) {
+                // would deadlock if the thread was still locked
+                assert_eq!(self.0.status(self.1), Some(Status::Exited(7)));
+                self.2.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let pool = Arc::new(ThreadPool::unbounded(RRScheduler::new(5)));
+        let tid = pool.add(Box::new(NoContext));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let probe = Probe(pool.clone(), tid, dropped.clone());
+        assert!(pool.init_local(tid, 0, Box::new(probe)).is_some());
+        pool.exit(tid, 7);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+
+    struct FakeClock(Arc<AtomicUsize>);
+
+    impl ClockEvent for FakeClock {
+        fn ticks(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+        fn set_next_event(&self, _cpu_id: usize, _ticks: Option<usize>) {}
+    }
+
+    #[test]
+    fn late_tick_charges_all() {
+        let now = Arc::new(AtomicUsize::new(0));
+        let pool =
+            ThreadPool::unbounded(RRScheduler::new(5)).with_clock_event(FakeClock(now.clone()));
+        let tid = pool.add(Box::new(NoContext));
+        let (tid1, context) = pool.run(0).unwrap();
+        assert_eq!(tid1, tid);
+        pool.start(tid);
+        // the interrupt comes 3 ticks after the time slice runs out
+        now.store(8, Ordering::Relaxed);
+        assert!(pool.tick(0, Some(tid)));
+        assert_eq!(pool.get_tick(tid), Some(8));
+        pool.sleep(tid, 0);
+        pool.stop(tid, context);
+        // CPU0 idles with no event, until another interrupt wakes the thread up
+        now.store(20, Ordering::Relaxed);
+        pool.wakeup(tid);
+        let (_, _context) = pool.run(0).unwrap();
+        // only charged since it runs again
+        now.store(22, Ordering::Relaxed);
+        assert!(!pool.tick(0, Some(tid)));
+        assert_eq!(pool.get_tick(tid), Some(10));
+    }
+
+    #[test]
+    fn one_shot_timer() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let (count, callback) = counter();
+        let handle = pool.add_timer(1, Duration::from_millis(30), callback);
+        run_ticks(&pool, 0, 10);
+        run_ticks(&pool, 1, 2);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        run_ticks(&pool, 1, 1);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        run_ticks(&pool, 1, 10);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        assert!(!pool.modify_timer(handle, Duration::from_millis(10)));
+        assert!(!pool.cancel_timer(handle));
+    }
+
+    #[test]
+    fn periodic_timer() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let (count, callback) = counter();
+        let handle = pool.add_periodic_timer(0, Duration::from_millis(20), callback);
+        run_ticks(&pool, 0, 7);
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        assert!(pool.cancel_timer(handle));
+        run_ticks(&pool, 0, 10);
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn modify_and_migrate_timer() {
+        let pool = ThreadPool::unbounded(RRScheduler::new(5));
+        let (count, callback) = counter();
+        let handle = pool.add_timer(0, Duration::from_millis(20), callback);
+        run_ticks(&pool, 0, 1);
+        assert!(pool.modify_timer(handle, Duration::from_millis(50)));
+        run_ticks(&pool, 0, 4);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        pool.migrate_timers(0, 1);
+        run_ticks(&pool, 0, 5);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        run_ticks(&pool, 1, 6);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
 }
@@ -0,0 +1,112 @@
+//! A growable table of thread slots
+//!
+//! Slots live in segments whose sizes double: segment `k` holds `SEGMENT_BASE << k` slots.
+//! A segment is allocated the first time an index inside it is handed out and never moves,
+//! so a reference to a slot stays valid for the lifetime of the table.
+//! Released indexes are kept in a free list, making allocation O(1).
+//!
+//! The table hands out `Tid`s which carry the slot index in the low half
+//! and a generation in the high half. The generation is bumped every time
+//! a slot is released, so an old `Tid` never matches the slot's next owner.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard, Once};
+
+type Tid = usize;
+
+/// Number of slots in the first segment.
+const SEGMENT_BASE: usize = 16;
+
+/// Number of low bits of a `Tid` used as the slot index.
+const INDEX_BITS: usize = core::mem::size_of::<usize>() * 8 / 2;
+
+/// Slot index of thread id `tid`.
+pub fn tid_index(tid: Tid) -> usize {
+    tid & ((1 << INDEX_BITS) - 1)
+}
+
+pub struct ThreadTable<T> {
+    /// Hard limit of the number of slots.
+    limit: usize,
+    /// Lazily allocated segments.
+    segments: Vec<Once<Box<[Mutex<Option<T>>]>>>,
+    /// Number of slots ever handed out. Slots beyond it are untouched.
+    next: AtomicUsize,
+    /// Released slots, as the `Tid` their next owner will get.
+    free: Mutex<Vec<Tid>>,
+}
+
+impl<T> ThreadTable<T> {
+    /// Create a table holding at most `limit` slots.
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.min(1 << INDEX_BITS);
+        let mut segments = Vec::new();
+        while segment_start(segments.len()) < limit {
+            segments.push(Once::new());
+        }
+        ThreadTable {
+            limit,
+            segments,
+            next: AtomicUsize::new(0),
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Get the slot at `index` if it has ever been allocated.
+    pub fn get(&self, index: usize) -> Option<&Mutex<Option<T>>> {
+        if index >= self.next.load(Ordering::Acquire).min(self.limit) {
+            return None;
+        }
+        let (segment, offset) = locate(index);
+        self.segments[segment].r#try().map(|s| &s[offset])
+    }
+
+    /// Iterate over all slots ever allocated, in the order of index.
+    pub fn iter(&self) -> impl Iterator<Item = &Mutex<Option<T>>> {
+        let len = self.next.load(Ordering::Acquire).min(self.limit);
+        (0..len).filter_map(move |index| self.get(index))
+    }
+
+    /// Take an empty slot, growing the table if needed.
+    /// Return the `Tid` of the slot's new owner,
+    /// or `None` if the hard limit is reached.
+    pub fn alloc(&self) -> Option<(Tid, MutexGuard<'_, Option<T>>)> {
+        if let Some(tid) = self.free.lock().pop() {
+            let slot = self.get(tid_index(tid)).expect("freed slot not exist").lock();
+            debug_assert!(slot.is_none());
+            return Some((tid, slot));
+        }
+        let index = self.next.fetch_add(1, Ordering::AcqRel);
+        if index >= self.limit {
+            self.next.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        let (segment, offset) = locate(index);
+        let slots = self.segments[segment].call_once(|| {
+            let mut slots = Vec::new();
+            slots.resize_with(SEGMENT_BASE << segment, Default::default);
+            slots.into_boxed_slice()
+        });
+        Some((index, slots[offset].lock()))
+    }
+
+    /// Give the emptied slot of `tid` back to the free list.
+    pub fn free(&self, tid: Tid) {
+        let next = tid.wrapping_add(1 << INDEX_BITS);
+        self.free.lock().push(next);
+    }
+}
+
+/// The first index of segment `k`.
+fn segment_start(k: usize) -> usize {
+    SEGMENT_BASE.saturating_mul((1usize << k).wrapping_sub(1))
+}
+
+/// Map an index to (segment, offset).
+fn locate(index: usize) -> (usize, usize) {
+    let q = index / SEGMENT_BASE + 1;
+    let k = (core::mem::size_of::<usize>() * 8 - 1) - q.leading_zeros() as usize;
+    (k, index - segment_start(k))
+}
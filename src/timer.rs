@@ -1,65 +1,504 @@
-//! A simple timer
+//! A hierarchical timing wheel
+//!
+//! Level `l` has `SLOTS` slots, each covering `SLOTS^l` ticks.
+//! An event lives in the level of the highest `LEVEL_BITS` group where its time differs
+//! from the current tick, and cascades down a level each time the wheel below wraps around.
+//! So starting, cancelling and firing an event are all O(1).
+//!
+//! Events are kept in a slab and linked into the slots by index,
+//! so they can be cancelled by the `TimerHandle` returned by `start_at`.
 
-use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
 
 type Time = usize;
 
-struct Event<T> {
+/// Number of bits of time handled by a level.
+const LEVEL_BITS: usize = 6;
+/// Number of slots in a level.
+const SLOTS: usize = 1 << LEVEL_BITS;
+/// Number of levels to cover the whole `Time`.
+const LEVELS: usize = (core::mem::size_of::<Time>() * 8 + LEVEL_BITS - 1) / LEVEL_BITS;
+/// The list of events already expired, after the lists of the slots.
+const DUE: usize = LEVELS * SLOTS;
+/// End of a list.
+const NIL: usize = usize::max_value();
+
+/// A handle to cancel an event started in `Timer`.
+///
+/// It becomes invalid once the event is popped or cancelled.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TimerHandle {
+    index: usize,
+    generation: usize,
+}
+
+struct Node<T> {
     time: Time,
     data: T,
+    /// The list it's linked in.
+    list: usize,
+    prev: usize,
+    next: usize,
 }
 
-/// A simple timer using ordered dequeue
+struct Entry<T> {
+    /// Bumped each time the entry is released, to invalidate old handles.
+    generation: usize,
+    node: Option<Node<T>>,
+}
+
+/// A timer using hierarchical timing wheel
 pub struct Timer<T> {
     tick: Time,
-    timers: VecDeque<Event<T>>,
+    /// Heads of the lists of all slots, then the due list.
+    heads: Vec<usize>,
+    /// Tail of the due list, which is popped in FIFO order.
+    due_tail: usize,
+    /// Non-empty slots of each level, as bit masks.
+    occupied: [u64; LEVELS],
+    entries: Vec<Entry<T>>,
+    free: Vec<usize>,
+    len: usize,
 }
 
-impl<T: PartialEq> Timer<T> {
+impl<T> Timer<T> {
     /// Create a new timer.
     pub fn new() -> Self {
         Timer {
             tick: 0,
-            timers: VecDeque::new(),
+            heads: vec![NIL; DUE + 1],
+            due_tail: NIL,
+            occupied: [0; LEVELS],
+            entries: Vec::new(),
+            free: Vec::new(),
+            len: 0,
         }
     }
+
+    /// The current tick.
+    pub fn now(&self) -> Time {
+        self.tick
+    }
+
     /// Called on each tick.
     pub fn tick(&mut self) {
-        self.tick += 1;
+        self.advance_to(self.tick.wrapping_add(1));
+    }
+
+    /// Advance the current tick until it's `now`,
+    /// so all events with time <= `now` are expired.
+    ///
+    /// It only stops at the ticks with events to cascade or expire,
+    /// so it's not proportional to the ticks skipped.
+    pub fn advance_to(&mut self, now: Time) {
+        while self.tick != now {
+            let left = now.wrapping_sub(self.tick);
+            match self.next_step() {
+                Some(ticks) if ticks <= left => {
+                    self.tick = self.tick.wrapping_add(ticks - 1);
+                    self.step();
+                }
+                _ => {
+                    // nothing to cascade or expire on the way
+                    self.tick = now;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Number of ticks until the next one `step` has anything to do at,
+    /// i.e. a slot to cascade down or expire. `None` if all slots are empty.
+    fn next_step(&self) -> Option<Time> {
+        let bits = core::mem::size_of::<Time>() * 8;
+        let mut next = None;
+        for level in 0..LEVELS {
+            let occupied = self.occupied[level];
+            if occupied == 0 {
+                continue;
+            }
+            // a slot is reached when the lower levels wrap around to 0
+            let shift = level * LEVEL_BITS;
+            let upper = shift + LEVEL_BITS;
+            let current = slot(self.tick, level);
+            let later = occupied & !((2u64 << current).wrapping_sub(1));
+            let mut at = if upper >= bits {
+                0
+            } else {
+                self.tick >> upper << upper
+            };
+            if later != 0 {
+                at = at.wrapping_add((later.trailing_zeros() as Time) << shift);
+            } else {
+                // in the next round of this level, only the top one wraps around
+                at = at.wrapping_add((occupied.trailing_zeros() as Time) << shift);
+                if upper < bits {
+                    at = at.wrapping_add(1 << upper);
+                }
+            }
+            let ticks = at.wrapping_sub(self.tick);
+            next = Some(next.map_or(ticks, |next: Time| next.min(ticks)));
+        }
+        next
+    }
+
+    /// Advance the current tick by one.
+    fn step(&mut self) {
+        self.tick = self.tick.wrapping_add(1);
+        let tick = self.tick;
+        // cascade from the highest level wrapping around,
+        // so the events come down through all the levels below
+        let mut top = 0;
+        for level in 1..LEVELS {
+            if tick & ((1 << (level * LEVEL_BITS)) - 1) != 0 {
+                break;
+            }
+            top = level;
+        }
+        for level in (1..=top).rev() {
+            let list = level * SLOTS + slot(tick, level);
+            let mut index = self.take_list(list);
+            while index != NIL {
+                let next = self.node(index).next;
+                self.place(index);
+                index = next;
+            }
+        }
+        // all events here are expired
+        let list = slot(tick, 0);
+        let mut index = self.take_list(list);
+        while index != NIL {
+            let next = self.node(index).next;
+            self.link(index, DUE);
+            index = next;
+        }
     }
+
     /// Pop an expired timer after `tick`.
     ///
-    /// This must be called after calling `tick`,
+    /// This must be called after calling `tick` or `advance_to`,
     /// and should be called multiple times until return `None`.
     pub fn pop(&mut self) -> Option<T> {
-        match self.timers.front() {
-            None => return None,
-            Some(timer) if timer.time != self.tick => return None,
-            _ => {}
+        let index = self.heads[DUE];
+        if index == NIL {
+            return None;
+        }
+        self.unlink(index);
+        Some(self.release(index))
+    }
+
+    /// Start a timer expiring at tick `time`.
+    /// If `time` has passed, it's expired right now.
+    ///
+    /// Times more than half the range of `Time` ahead are taken as in the past.
+    pub fn start_at(&mut self, time: Time, data: T) -> TimerHandle {
+        let time = if time.wrapping_sub(self.tick) > Time::max_value() / 2 {
+            // before now
+            self.tick
+        } else {
+            time
         };
-        self.timers.pop_front().map(|t| t.data)
-    }
-    /// Start a timer with given time interval
-    pub fn start(&mut self, time_after: Time, data: T) {
-        //debug!("{:?} {:?}", self.tick, time_after);
-        let time = self.tick + time_after;
-        let event = Event { time, data };
-        let mut it = self.timers.iter();
-        let mut i: usize = 0;
-        loop {
-            match it.next() {
-                None => break,
-                Some(e) if e.time >= time => break,
-                _ => {}
+        let node = Node {
+            time,
+            data,
+            list: NIL,
+            prev: NIL,
+            next: NIL,
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.entries[index].node = Some(node);
+                index
+            }
+            None => {
+                self.entries.push(Entry {
+                    generation: 0,
+                    node: Some(node),
+                });
+                self.entries.len() - 1
+            }
+        };
+        self.len += 1;
+        self.place(index);
+        TimerHandle {
+            index,
+            generation: self.entries[index].generation,
+        }
+    }
+
+    /// Cancel the timer of `handle`.
+    /// Return its data, or `None` if it has been popped or cancelled.
+    pub fn cancel(&mut self, handle: TimerHandle) -> Option<T> {
+        match self.entries.get(handle.index) {
+            Some(entry) if entry.generation == handle.generation && entry.node.is_some() => {}
+            _ => return None,
+        }
+        self.unlink(handle.index);
+        Some(self.release(handle.index))
+    }
+
+    /// Remove all timers, including expired ones not popped yet.
+    /// Return them with their handles and expiring time.
+    pub fn drain(&mut self) -> Vec<(TimerHandle, Time, T)> {
+        let mut timers = Vec::new();
+        for index in 0..self.entries.len() {
+            if self.entries[index].node.is_none() {
+                continue;
             }
-            i += 1;
+            let handle = TimerHandle {
+                index,
+                generation: self.entries[index].generation,
+            };
+            let time = self.node(index).time;
+            self.unlink(index);
+            timers.push((handle, time, self.release(index)));
         }
-        self.timers.insert(i, event);
+        timers
     }
-    /// Stop a timer
-    pub fn stop(&mut self, data: T) {
-        if let Some(i) = self.timers.iter().position(|t| t.data == data) {
-            self.timers.remove(i);
+
+    /// The earliest time a pending timer expires, or `None` if there is none.
+    /// The current tick if some timers have expired but not popped yet.
+    pub fn next_deadline(&self) -> Option<Time> {
+        if self.heads[DUE] != NIL {
+            return Some(self.tick);
+        }
+        // Events at a lower level expire earlier,
+        // and within a level their slots are after the current one.
+        for level in 0..LEVELS {
+            let current = slot(self.tick, level);
+            // `2 << 63` is 0, leaving no later slot
+            let later = self.occupied[level] & !((2u64 << current).wrapping_sub(1));
+            if later == 0 {
+                continue;
+            }
+            let list = level * SLOTS + later.trailing_zeros() as usize;
+            if level == 0 {
+                return Some(self.node(self.heads[list]).time);
+            }
+            // a higher slot is not sorted
+            let mut earliest = Time::max_value();
+            let mut index = self.heads[list];
+            while index != NIL {
+                let node = self.node(index);
+                earliest = earliest.min(node.time);
+                index = node.next;
+            }
+            return Some(earliest);
         }
+        None
+    }
+
+    fn node(&self, index: usize) -> &Node<T> {
+        self.entries[index].node.as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut Node<T> {
+        self.entries[index].node.as_mut().unwrap()
+    }
+
+    /// Link the unlinked node `index` to the list its time belongs to now.
+    fn place(&mut self, index: usize) {
+        let time = self.node(index).time;
+        let list = if time == self.tick {
+            DUE
+        } else {
+            let bits = core::mem::size_of::<Time>() * 8;
+            let level = (bits - 1 - (time ^ self.tick).leading_zeros() as usize) / LEVEL_BITS;
+            level * SLOTS + slot(time, level)
+        };
+        self.link(index, list);
+    }
+
+    /// Append node `index` to `list`.
+    fn link(&mut self, index: usize, list: usize) {
+        let prev = if list == DUE {
+            self.due_tail
+        } else {
+            // order doesn't matter in the slots, push to the front
+            NIL
+        };
+        let next = if list == DUE { NIL } else { self.heads[list] };
+        {
+            let node = self.node_mut(index);
+            node.list = list;
+            node.prev = prev;
+            node.next = next;
+        }
+        if prev == NIL {
+            self.heads[list] = index;
+        } else {
+            self.node_mut(prev).next = index;
+        }
+        if next != NIL {
+            self.node_mut(next).prev = index;
+        }
+        if list == DUE {
+            self.due_tail = index;
+        } else {
+            self.occupied[list / SLOTS] |= 1 << (list % SLOTS);
+        }
+    }
+
+    /// Remove node `index` from its list.
+    fn unlink(&mut self, index: usize) {
+        let (list, prev, next) = {
+            let node = self.node(index);
+            (node.list, node.prev, node.next)
+        };
+        if prev == NIL {
+            self.heads[list] = next;
+        } else {
+            self.node_mut(prev).next = next;
+        }
+        if next == NIL {
+            if list == DUE {
+                self.due_tail = prev;
+            }
+        } else {
+            self.node_mut(next).prev = prev;
+        }
+        if list != DUE && self.heads[list] == NIL {
+            self.occupied[list / SLOTS] &= !(1 << (list % SLOTS));
+        }
+    }
+
+    /// Detach the whole slot list `list`, returning its first node.
+    /// The nodes keep their links to each other.
+    fn take_list(&mut self, list: usize) -> usize {
+        self.occupied[list / SLOTS] &= !(1 << (list % SLOTS));
+        core::mem::replace(&mut self.heads[list], NIL)
+    }
+
+    /// Free the unlinked node `index`, returning its data.
+    fn release(&mut self, index: usize) -> T {
+        let entry = &mut self.entries[index];
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free.push(index);
+        self.len -= 1;
+        entry.node.take().unwrap().data
+    }
+}
+
+/// The slot of `time` at `level`.
+fn slot(time: Time, level: usize) -> usize {
+    (time >> (level * LEVEL_BITS)) & (SLOTS - 1)
+}
+
+#[cfg(all(test, feature = "userland"))]
+mod tests {
+    use super::*;
+
+    fn start(timer: &mut Timer<usize>, time_after: Time, data: usize) -> TimerHandle {
+        let time = timer.now() + time_after;
+        timer.start_at(time, data)
+    }
+
+    fn expire(timer: &mut Timer<usize>) -> Vec<usize> {
+        timer.tick();
+        let mut events = Vec::new();
+        while let Some(event) = timer.pop() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn fire_in_order() {
+        let mut timer = Timer::new();
+        let times = [1, 2, 63, 64, 65, 100, 4095, 4096, 5000];
+        for &t in times.iter().rev() {
+            start(&mut timer, t, t);
+        }
+        let mut fired = Vec::new();
+        for now in 1..=5000 {
+            for event in expire(&mut timer) {
+                assert_eq!(event, now);
+                fired.push(event);
+            }
+        }
+        assert_eq!(fired, times.to_vec());
+        assert_eq!(timer.next_deadline(), None);
+    }
+
+    #[test]
+    fn cancel_by_handle() {
+        let mut timer = Timer::new();
+        let a = start(&mut timer, 10, 1);
+        let b = start(&mut timer, 10, 2);
+        assert_eq!(timer.cancel(a), Some(1));
+        assert_eq!(timer.cancel(a), None);
+        timer.advance_to(10);
+        assert_eq!(timer.pop(), Some(2));
+        assert_eq!(timer.pop(), None);
+        assert_eq!(timer.cancel(b), None);
+        // a new event reusing the entry is not touched by the old handle
+        start(&mut timer, 1, 3);
+        assert_eq!(timer.cancel(a), None);
+        assert_eq!(timer.next_deadline(), Some(11));
+    }
+
+    #[test]
+    fn catch_up_and_past_events() {
+        let mut timer = Timer::new();
+        start(&mut timer, 3, 3);
+        start(&mut timer, 70, 70);
+        timer.advance_to(100);
+        assert_eq!(timer.pop(), Some(3));
+        assert_eq!(timer.pop(), Some(70));
+        timer.start_at(50, 50);
+        start(&mut timer, 0, 0);
+        assert_eq!(timer.next_deadline(), Some(100));
+        assert_eq!(timer.pop(), Some(50));
+        assert_eq!(timer.pop(), Some(0));
+        assert_eq!(timer.pop(), None);
+    }
+
+    #[test]
+    fn advance_far() {
+        let mut timer = Timer::new();
+        let times = [1, 63, 64, 200, 4096, 300_000, 1 << 40];
+        for &t in times.iter().rev() {
+            start(&mut timer, t, t);
+        }
+        let mut fired = Vec::new();
+        for &now in [0, 100, 4095, 4096, 1 << 30, 1 << 41].iter() {
+            timer.advance_to(now);
+            assert_eq!(timer.now(), now);
+            while let Some(event) = timer.pop() {
+                assert!(event <= now);
+                fired.push(event);
+            }
+            assert!(timer.next_deadline().map_or(true, |deadline| deadline > now));
+        }
+        assert_eq!(fired, times.to_vec());
+        // across the wrap around
+        let near = Time::max_value() - 10;
+        timer.advance_to(near);
+        start(&mut timer, 5, 5);
+        timer.start_at(100, 100);
+        timer.advance_to(50);
+        assert_eq!(timer.pop(), Some(5));
+        assert_eq!(timer.pop(), None);
+        timer.advance_to(100);
+        assert_eq!(timer.pop(), Some(100));
+    }
+
+    #[test]
+    fn next_deadline() {
+        let mut timer = Timer::new();
+        assert_eq!(timer.next_deadline(), None);
+        start(&mut timer, 5000, 5000);
+        start(&mut timer, 300, 300);
+        start(&mut timer, 200, 200);
+        assert_eq!(timer.next_deadline(), Some(200));
+        timer.advance_to(150);
+        assert_eq!(timer.next_deadline(), Some(200));
+        start(&mut timer, 20, 170);
+        assert_eq!(timer.next_deadline(), Some(170));
+        timer.advance_to(4999);
+        assert_eq!(timer.next_deadline(), Some(4999));
+        while timer.pop().is_some() {}
+        assert_eq!(timer.next_deadline(), Some(5000));
     }
 }
@@ -0,0 +1,84 @@
+//! A queue of threads waiting for something
+
+use crate::thread_pool::{ThreadPool, Tid};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// A FIFO queue of sleeping threads.
+///
+/// A thread puts itself into the queue by `wait`, then yields.
+/// Other threads wake it up by `notify_one` or `notify_all`.
+///
+/// A notification sent between `wait` and the yield is not lost:
+/// the thread will just be put back to the ready queue when it stops.
+#[derive(Default)]
+pub struct WaitQueue {
+    queue: Mutex<VecDeque<Tid>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue::default()
+    }
+
+    /// Put thread `tid` to sleep and append it to the queue.
+    ///
+    /// The thread must yield afterwards to actually sleep.
+    pub fn wait(&self, pool: &ThreadPool, tid: Tid) {
+        pool.sleep(tid, 0);
+        self.push(tid);
+    }
+
+    /// Append thread `tid` to the queue without touching its status.
+    pub(crate) fn push(&self, tid: Tid) {
+        self.queue.lock().push_back(tid);
+    }
+
+    /// Wake up the first thread in the queue.
+    ///
+    /// Threads which are no longer sleeping (e.g. woken up by others) are skipped.
+    /// Return whether a thread was woken up.
+    pub fn notify_one(&self, pool: &ThreadPool) -> bool {
+        loop {
+            let tid = match self.queue.lock().pop_front() {
+                Some(tid) => tid,
+                None => return false,
+            };
+            if pool.wakeup(tid) {
+                return true;
+            }
+        }
+    }
+
+    /// Wake up all threads in the queue.
+    /// Return the number of threads woken up.
+    pub fn notify_all(&self, pool: &ThreadPool) -> usize {
+        let queue = core::mem::replace(&mut *self.queue.lock(), VecDeque::new());
+        queue.into_iter().filter(|&tid| pool.wakeup(tid)).count()
+    }
+
+    /// Remove thread `tid` from the queue.
+    /// Return whether it was in the queue.
+    pub fn remove(&self, tid: Tid) -> bool {
+        let mut queue = self.queue.lock();
+        match queue.iter().position(|&t| t == tid) {
+            Some(i) => {
+                queue.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The threads in the queue, the first to be notified first.
+    /// Some of them may have been woken up by others.
+    pub fn waiters(&self) -> Vec<Tid> {
+        self.queue.lock().iter().cloned().collect()
+    }
+
+    /// Return whether no thread is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+}